@@ -1,12 +1,13 @@
 use near_sdk::{
     self,
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::{LookupSet, Vector, UnorderedMap},
-    Balance, PublicKey,
+    collections::{LookupMap, LookupSet, Vector, UnorderedMap, UnorderedSet},
+    Balance, BorshStorageKey, PublicKey,
 };
 use near_sdk::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use near_sdk::{
-    assert_one_yocto, env, is_promise_success, json_types::U128, json_types::U64, near_bindgen, AccountId, Gas, PanicOnDefault, Promise, PromiseResult, serde_json::json
+    assert_one_yocto, env, is_promise_success, json_types::U128, json_types::U64, near_bindgen, AccountId, Gas, PanicOnDefault, Promise, PromiseResult, PromiseOrValue, serde_json::json
 };
 
 // Constants
@@ -15,11 +16,272 @@ pub const STORE_BALANCE: u128 = 7_000_000_000_000_000_000_000_000;
 pub const ONE_YOCTO: u128 = 10_000_000_000_000_000_000_000;
 pub const NO_DEPOSIT: Balance = 0;
 pub const TGAS: u64 = 1_000_000_000_000;
+pub const BASIS_POINTS: u128 = 10_000;
+pub const DEFAULT_PAGE_LIMIT: u64 = 50;
+pub const MAX_PAGE_SCAN: u64 = 500;
+pub const DEFAULT_CONFIRMATION_WINDOW_DAYS: u64 = 14;
+pub const DEFAULT_PLATFORM_FEE_BPS: u16 = 200;
+pub const MAX_PLATFORM_FEE_BPS: u16 = 2_000;
+pub const DEFAULT_REFERRAL_FEE_BPS: u16 = 0;
+pub const MIN_STORE_PREFIX_LEN: usize = 2;
+pub const MAX_STORE_PREFIX_LEN: usize = 32;
+pub const DEFAULT_STORE_NAME_COOLDOWN_DAYS: u64 = 30;
+pub const STORAGE_REGISTRATION_BYTES: u64 = 200;
+pub const PURCHASE_STORAGE_BYTES: u64 = 1_000;
+pub const SHIPPING_STORAGE_BYTES: u64 = 100;
+pub const STORE_REGISTRY_STORAGE_BYTES: u64 = 1_000;
+pub const MAX_CART_LINES: usize = 5;
+// Orders make one call per token to burn or unlock them, so their size bounds the gas.
+pub const MAX_ORDER_QUANTITY: u32 = 10;
+pub const REVIEW_STORAGE_BYTES: u64 = 300;
+pub const DEFAULT_RESERVED_NAMES: [&str; 4] = ["market", "pipar", "dao", "auction"];
+// Store entry points the factory relies on that not every store code version exports.
+pub const STORE_ENTRY_POINTS: [&str; 3] = ["upgrade", "close_store", "burn_token"];
 
 pub const fn tgas(n: u64) -> Gas {
     Gas(n * 10u64.pow(12))
 }
 pub const PGAS: Gas = tgas(35 + 5);
+pub const FT_TRANSFER_GAS: Gas = tgas(10);
+pub const FT_TRANSFER_DEPOSIT: Balance = 1;
+pub const MIGRATE_GAS_RESERVE: Gas = tgas(10);
+pub const DEFAULT_MIGRATION_BATCH: u64 = 25;
+// Protocol fees for a deploy action: the send and execution fees per code byte, and a
+// round-up of the base fees.
+pub const DEPLOY_GAS_PER_BYTE: Gas = Gas(72_000_000);
+pub const DEPLOY_BASE_GAS: Gas = tgas(1);
+pub const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+// Room for a store's `upgrade` call and its migration, on top of redeploying the code.
+pub const STORE_MIGRATE_GAS: Gas = tgas(20);
+pub const UPGRADE_STORE_CALLBACK_GAS: Gas = tgas(5);
+// Protocol fees for sending code as function call arguments, per byte.
+pub const FUNCTION_CALL_GAS_PER_BYTE: Gas = Gas(4_500_000);
+pub const MAX_STORE_UPGRADE_BATCH: usize = 10;
+pub const BURN_TOKEN_GAS: Gas = tgas(10);
+pub const UNLOCK_TOKEN_GAS: Gas = tgas(10);
+// Settling a delivery before any per-token unlocks, with room for every FT payout.
+pub const COMPLETE_PURCHASE_GAS: Gas = tgas(100);
+pub const BUNDLED_STORE_CODE: &[u8] = include_bytes!("../wasm/store.wasm");
+
+pub const EVENT_STANDARD: &str = "pipar_marketplace";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn read_leb_u32(code: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut result: u32 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *code.get(*pos)?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+        shift += 7;
+        if shift >= 35 {
+            return None;
+        }
+    }
+}
+
+// Names of the functions a wasm module exports, or `None` if it is not a valid module.
+pub(crate) fn wasm_exported_functions(code: &[u8]) -> Option<Vec<String>> {
+    if code.len() < 8 || &code[..4] != b"\0asm" {
+        return None;
+    }
+    let mut pos = 8;
+    while pos < code.len() {
+        let section_id = code[pos];
+        pos += 1;
+        let size = read_leb_u32(code, &mut pos)? as usize;
+        let end = pos.checked_add(size).filter(|end| *end <= code.len())?;
+        if section_id == 7 {
+            let mut names = vec![];
+            for _ in 0..read_leb_u32(code, &mut pos)? {
+                let len = read_leb_u32(code, &mut pos)? as usize;
+                let name = code.get(pos..pos.checked_add(len)?)?;
+                pos += len;
+                let kind = *code.get(pos)?;
+                pos += 1;
+                read_leb_u32(code, &mut pos)?;
+                if kind == 0 {
+                    names.push(String::from_utf8(name.to_vec()).ok()?);
+                }
+            }
+            return Some(names);
+        }
+        pos = end;
+    }
+    Some(vec![])
+}
+
+pub(crate) fn emit_event(event: &str, data: serde_json::Value) {
+    let log = json!({
+        "standard": EVENT_STANDARD,
+        "version": EVENT_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log_str(&format!("EVENT_JSON:{}", log));
+}
+
+pub(crate) fn emit_order_event(event: &str, t: &Transaction) {
+    emit_event(event, json!({
+        "transaction_id": t.transaction_id,
+        "order_reference": t.order_reference,
+        "store_contract_id": t.store_contract_id,
+        "buyer_id": t.buyer_id,
+        "product_id": t.product_id,
+        "token_id": t.token_id,
+        "buyer_value_locked": t.buyer_value_locked,
+        "ft_token_id": t.ft_token_id,
+        "status": t.status,
+        "cart_id": t.cart_id,
+        "quantity": t.quantity,
+    }));
+}
+
+// Overflow-safe `amount * bps / BASIS_POINTS`, rounded down.
+pub(crate) fn bps_of(amount: u128, bps: u128) -> u128 {
+    amount / BASIS_POINTS * bps + amount % BASIS_POINTS * bps / BASIS_POINTS
+}
+
+// Affiliate and royalty shares are paid out of the seller's funds, so together they may
+// not exceed them; checking this up front keeps every later release from failing.
+pub(crate) fn check_payout_shares(result: &MarketplaceData) -> Result<(), String> {
+    let affiliate_bps = match (result.affiliate, &result.affiliate_id) {
+        (true, Some(_)) => result.affiliate_percentage.unwrap_or(0) as u128 * 100,
+        _ => 0,
+    };
+    let royalty_bps: u128 = result.royalty.values().map(|bps| *bps as u128).sum();
+    if affiliate_bps + royalty_bps > BASIS_POINTS {
+        return Err(format!(
+            "Affiliate and royalty shares add up to {} basis points, more than the seller's funds",
+            affiliate_bps + royalty_bps
+        ));
+    }
+    Ok(())
+}
+
+// The store prices a single unit and has to mint one token for every unit ordered.
+pub(crate) fn check_mint(result: &MarketplaceData, amount: u128, quantity: u32) -> Result<(), String> {
+    let minted = result.minted_token_ids().len();
+    if minted != quantity as usize {
+        return Err(format!("The store minted {} tokens for {} units", minted, quantity));
+    }
+    match result.price.checked_mul(quantity as u128) {
+        Some(expected) if amount >= expected => {}
+        _ => return Err(format!("{} does not cover {} units at {} each", amount, quantity, result.price)),
+    }
+    check_payout_shares(result)
+}
+
+pub(crate) fn deploy_gas(code_len: usize) -> Gas {
+    Gas(DEPLOY_BASE_GAS.0 + DEPLOY_GAS_PER_BYTE.0 * code_len as u64)
+}
+
+// Gas that can be attached to `migrate` once the redeploy of `code_len` bytes and the rest
+// of the upgrade call are paid for, or `None` if the prepaid gas does not cover them.
+pub(crate) fn upgrade_migrate_gas(prepaid: Gas, used: Gas, code_len: usize) -> Option<Gas> {
+    prepaid.0
+        .checked_sub(used.0)?
+        .checked_sub(deploy_gas(code_len).0)?
+        .checked_sub(MIGRATE_GAS_RESERVE.0)
+        .map(Gas)
+}
+
+pub(crate) fn store_upgrade_gas(code_len: usize) -> Gas {
+    Gas(deploy_gas(code_len).0 + STORE_MIGRATE_GAS.0)
+}
+
+// Gas the factory needs to upgrade `count` stores to code of `code_len` bytes: sending the
+// code, the gas attached to each store's `upgrade` and the callbacks.
+pub(crate) fn store_upgrade_batch_gas(code_len: usize, count: usize) -> Gas {
+    let per_store = FUNCTION_CALL_GAS_PER_BYTE.0 * code_len as u64
+        + store_upgrade_gas(code_len).0
+        + UPGRADE_STORE_CALLBACK_GAS.0;
+    Gas(per_store * count as u64)
+}
+
+pub(crate) fn assert_order_quantity(quantity: u32) {
+    assert!(
+        quantity > 0 && quantity <= MAX_ORDER_QUANTITY,
+        "Quantity must be between 1 and {}",
+        MAX_ORDER_QUANTITY
+    );
+}
+
+pub(crate) fn all_promises_succeeded() -> bool {
+    (0..env::promise_results_count()).all(|i| matches!(env::promise_result(i), PromiseResult::Successful(_)))
+}
+
+pub(crate) fn emit_payout_event(transaction_id: Option<U128>, receiver_id: &AccountId, amount: u128, kind: &str) {
+    emit_event("payout_sent", json!({
+        "transaction_id": transaction_id,
+        "receiver_id": receiver_id,
+        "amount": U128::from(amount),
+        "kind": kind,
+    }));
+}
+
+#[derive(BorshStorageKey, BorshSerialize)]
+pub enum StorageKey {
+    Transactions,
+    TransactionsByBuyer,
+    BuyerTransactions { account_hash: Vec<u8> },
+    TransactionsByStore,
+    StoreTransactions { account_hash: Vec<u8> },
+    OpenPurchases,
+    OrderReferences,
+    AcceptedTokens,
+    Roles,
+    RoleMembers { role: Role },
+    StoreCodeVersions,
+    StoreCode,
+    StoreRegistry,
+    CategoryFees,
+    StoresByOwner,
+    FeeBalances,
+    OwnerStores { account_hash: Vec<u8> },
+    ReservedNames,
+    ArchivedStores,
+    ReleasedPrefixes,
+    KeypomContracts,
+    StorageAccounts,
+    CartTransactions,
+    ReturnPolicies,
+    Tracking,
+    TrackingUpdates { transaction_id: u128 },
+    Reviews,
+    StoreReviews,
+    StoreReviewIds { account_hash: Vec<u8> },
+    StoreReputations,
+    ClaimableBalances,
+    OpenEscrowCounts,
+    ArchivedStoreTransactions,
+    ArchivedStoreReviews,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum StateVersion {
+    V1,
+    V2,
+}
+
+pub const CURRENT_STATE_VERSION: StateVersion = StateVersion::V2;
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    Admin,
+    Operator,
+    Arbiter,
+    Verifier,
+}
 
 #[near_bindgen]
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Eq, PartialEq, Clone, Debug)]
@@ -30,12 +292,421 @@ pub enum TransactionStatus {
     Delivered,
     Disputed,
     Canceled,
+    Resolved,
+    ReturnRequested,
+    Returned,
+}
+
+impl TransactionStatus {
+    pub fn is_open(&self) -> bool {
+        match self {
+            TransactionStatus::Approved
+            | TransactionStatus::Shipped
+            | TransactionStatus::Disputed
+            | TransactionStatus::ReturnRequested => true,
+            TransactionStatus::Delivered
+            | TransactionStatus::Canceled
+            | TransactionStatus::Resolved
+            | TransactionStatus::Returned => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ReturnShippingPayer {
+    Buyer,
+    Seller,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReturnPolicy {
+    pub window_days: u64,
+    pub restocking_fee_bps: u16,
+    pub return_shipping_paid_by: ReturnShippingPayer,
+    pub return_shipping_fee: U128,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReturnRequest {
+    pub reason: String,
+    pub requested_at: U64,
+    pub shipped_at: Option<U64>,
+    pub ipfs: Option<String>,
+    pub received_at: Option<U64>,
+    pub buyer_refund: Option<U128>,
+    pub seller_payout: Option<U128>,
 }
 
 #[near_bindgen]
-#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug)]
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DisputeResolution {
+    pub arbiter_id: AccountId,
+    pub buyer_share_bps: u16,
+    pub buyer_payout: U128,
+    pub seller_payout: U128,
+    pub reason: String,
+    pub resolved_at: U64,
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Transaction {
+    pub transaction_id: U128,
+    pub order_reference: String,
+    pub created_at: U64,
+    pub product_id: U64,
+    pub store_contract_id: AccountId,
+    pub buyer_id: AccountId,
+    pub buyer_value_locked: U128,
+    pub price: Balance,
+    pub token_id: String,
+    pub timeout: U128,
+    pub affiliate: bool,
+    pub affiliate_id: Option<AccountId>,
+    pub affiliate_percentage: Option<u32>,
+    pub is_discount: bool,
+    pub is_reward: bool,
+    pub is_keypom: bool,
+    pub status: TransactionStatus,
+    pub hashed_billing_address: String,
+    pub nonce: String,
+    pub ipfs: String,
+    pub dispute_resolution: Option<DisputeResolution>,
+    pub cancellation_requested: bool,
+    pub ft_token_id: Option<AccountId>,
+    pub shipped_at: Option<U64>,
+    pub confirmation_deadline: Option<U64>,
+    pub platform_fee_bps: u16,
+    pub referrer_id: Option<AccountId>,
+    pub referral_fee_bps: u16,
+    pub royalties: Vec<(AccountId, u32)>,
+    pub buyer_storage_bytes: u64,
+    pub store_storage_bytes: u64,
+    pub cart_id: Option<U64>,
+    pub quantity: u32,
+    pub token_ids: Vec<String>,
+    pub shipped_quantity: u32,
+    pub delivered_quantity: u32,
+    pub released_value: U128,
+    pub return_policy: Option<ReturnPolicy>,
+    pub return_deadline: Option<U64>,
+    pub return_request: Option<ReturnRequest>,
+}
+
+impl Transaction {
+    pub fn escrow_balance(&self) -> u128 {
+        u128::from(self.buyer_value_locked) - u128::from(self.released_value)
+    }
+
+    pub fn holds_escrow(&self) -> bool {
+        self.status.is_open() || self.escrow_balance() > 0
+    }
+
+    // Value of the first `units` units, so that repeated partial releases add up to
+    // exactly the locked amount without rounding leftovers.
+    pub fn value_of_units(&self, units: u32) -> u128 {
+        let locked: u128 = self.buyer_value_locked.into();
+        locked / self.quantity as u128 * units as u128 + locked % self.quantity as u128 * units as u128 / self.quantity as u128
+    }
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TrackingUpdate {
+    pub carrier: Option<String>,
+    pub tracking_code_hash: Option<String>,
+    pub ipfs: String,
+    pub timestamp: U64,
+    pub author_id: AccountId,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReviewReply {
+    pub ipfs: String,
+    pub author_id: AccountId,
+    pub created_at: U64,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Review {
+    pub transaction_id: U128,
+    pub store_contract_id: AccountId,
+    pub buyer_id: AccountId,
+    pub product_id: U64,
+    pub rating: u8,
+    pub ipfs: String,
+    pub created_at: U64,
+    pub reply: Option<ReviewReply>,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StoreReputation {
+    pub review_count: u64,
+    pub rating_total: u64,
+    pub rating_counts: [u64; 5],
+    // Average rating in hundredths, e.g. 450 for 4.5 stars.
+    pub average_rating: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StoreCreationQuote {
+    pub store_balance: U128,
+    pub registry_storage: U128,
+    pub creation_fee: U128,
+    pub total: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StorageAccount {
+    pub deposit: u128,
+    pub used_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StoreCodeVersion {
+    pub version: u32,
+    pub code_hash: String,
+    pub release_notes: String,
+    pub is_default: bool,
+    pub created_at: U64,
+    pub entry_points: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StoreRecord {
+    pub store_id: AccountId,
+    pub prefix: String,
+    pub owner_id: AccountId,
+    pub name: String,
+    pub category: String,
+    pub city: String,
+    pub state: String,
+    pub country: String,
+    pub code_hash: String,
+    pub created_at: U64,
+    pub is_keypom: bool,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ArchivedStore {
+    pub store: StoreRecord,
+    pub closed_by: AccountId,
+    pub closed_at: U64,
+    pub stats: StoreStats,
+    pub reputation: StoreReputation,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutKind {
+    Store,
+    Affiliate,
+    Royalty,
+    Referrer,
+    Platform,
+    Buyer,
+}
+
+impl PayoutKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PayoutKind::Store => "store",
+            PayoutKind::Affiliate => "affiliate",
+            PayoutKind::Royalty => "royalty",
+            PayoutKind::Referrer => "referrer",
+            PayoutKind::Platform => "platform",
+            PayoutKind::Buyer => "buyer",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Payout {
+    pub receiver_id: AccountId,
+    pub amount: U128,
+    pub kind: PayoutKind,
+}
+
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StoreStats {
+    pub order_count: u64,
+    pub gross_volume: U128,
+    pub ft_gross_volume: HashMap<AccountId, U128>,
+    pub shipped_count: u64,
+    pub delivered_count: u64,
+    pub refunded_count: u64,
+    pub disputed_count: u64,
+    pub resolved_count: u64,
+    pub return_requested_count: u64,
+    pub returned_count: u64,
+    pub total_ship_time: U64,
+    pub average_ship_time: U64,
+}
+
+impl Default for StoreStats {
+    fn default() -> Self {
+        Self {
+            order_count: 0,
+            gross_volume: U128(0),
+            ft_gross_volume: HashMap::new(),
+            shipped_count: 0,
+            delivered_count: 0,
+            refunded_count: 0,
+            disputed_count: 0,
+            resolved_count: 0,
+            return_requested_count: 0,
+            returned_count: 0,
+            total_ship_time: U64(0),
+            average_ship_time: U64(0),
+        }
+    }
+}
+
+// Each layout a transaction was ever stored with keeps its own variant; older variants are
+// frozen and converted to the current `Transaction` when read.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum VersionedTransaction {
+    V1(Box<TransactionV1>),
+    V2(Box<Transaction>),
+}
+
+impl From<VersionedTransaction> for Transaction {
+    fn from(transaction: VersionedTransaction) -> Self {
+        match transaction {
+            VersionedTransaction::V1(t) => (*t).into(),
+            VersionedTransaction::V2(t) => *t,
+        }
+    }
+}
+
+impl From<Transaction> for VersionedTransaction {
+    fn from(transaction: Transaction) -> Self {
+        VersionedTransaction::V2(Box::new(transaction))
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct TransactionV1 {
+    pub transaction_id: U128,
+    pub order_reference: String,
+    pub created_at: U64,
+    pub product_id: U64,
+    pub store_contract_id: AccountId,
+    pub buyer_id: AccountId,
+    pub buyer_value_locked: U128,
+    pub price: Balance,
+    pub token_id: String,
+    pub timeout: U128,
+    pub affiliate: bool,
+    pub affiliate_id: Option<AccountId>,
+    pub affiliate_percentage: Option<u32>,
+    pub is_discount: bool,
+    pub is_reward: bool,
+    pub is_keypom: bool,
+    pub status: TransactionStatus,
+    pub hashed_billing_address: String,
+    pub nonce: String,
+    pub ipfs: String,
+    pub dispute_resolution: Option<DisputeResolution>,
+    pub cancellation_requested: bool,
+    pub ft_token_id: Option<AccountId>,
+}
+
+// V1 orders are single units and were never charged storage; closed ones have released
+// their escrow. The confirmation window is set by the migration, which knows its length.
+impl From<TransactionV1> for Transaction {
+    fn from(t: TransactionV1) -> Self {
+        let shipped_quantity = match t.status {
+            TransactionStatus::Approved | TransactionStatus::Canceled => 0,
+            _ => 1,
+        };
+        let delivered_quantity = if t.status == TransactionStatus::Delivered { 1 } else { 0 };
+        let released_value = if t.status.is_open() { U128(0) } else { t.buyer_value_locked };
+        Transaction {
+            transaction_id: t.transaction_id,
+            order_reference: t.order_reference,
+            created_at: t.created_at,
+            product_id: t.product_id,
+            store_contract_id: t.store_contract_id,
+            buyer_id: t.buyer_id,
+            buyer_value_locked: t.buyer_value_locked,
+            price: t.price,
+            token_ids: vec![t.token_id.clone()],
+            token_id: t.token_id,
+            timeout: t.timeout,
+            affiliate: t.affiliate,
+            affiliate_id: t.affiliate_id,
+            affiliate_percentage: t.affiliate_percentage,
+            is_discount: t.is_discount,
+            is_reward: t.is_reward,
+            is_keypom: t.is_keypom,
+            status: t.status,
+            hashed_billing_address: t.hashed_billing_address,
+            nonce: t.nonce,
+            ipfs: t.ipfs,
+            dispute_resolution: t.dispute_resolution,
+            cancellation_requested: t.cancellation_requested,
+            ft_token_id: t.ft_token_id,
+            shipped_at: None,
+            confirmation_deadline: None,
+            platform_fee_bps: DEFAULT_PLATFORM_FEE_BPS,
+            referrer_id: None,
+            referral_fee_bps: 0,
+            royalties: vec![],
+            buyer_storage_bytes: 0,
+            store_storage_bytes: 0,
+            cart_id: None,
+            quantity: 1,
+            shipped_quantity,
+            delivered_quantity,
+            released_value,
+            return_policy: None,
+            return_deadline: None,
+            return_request: None,
+        }
+    }
+}
+
+// Orders `migrate` left for `migrate_transactions` to move over in batches.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum PendingMigration {
+    // Legacy orders still to be copied, taken from the back of the legacy vector.
+    Legacy { transactions: Vector<LegacyTransaction> },
+    // Orders stored in the V1 layout, by their index in the transactions map.
+    V1 { next_index: u64, end_index: u64 },
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyTransaction {
     pub transaction_id: U128,
     pub product_id: U64,
     pub store_contract_id: AccountId,
@@ -53,7 +724,72 @@ pub struct Transaction {
     pub status: TransactionStatus,
     pub hashed_billing_address: String,
     pub nonce: String,
-    pub ipfs: String
+    pub ipfs: String,
+}
+
+impl LegacyTransaction {
+    // Legacy ids were block timestamps, so they become the creation time of the migrated
+    // order while the order itself is renumbered.
+    pub fn into_v1(self, transaction_id: u128, order_reference: String) -> TransactionV1 {
+        TransactionV1 {
+            transaction_id: U128::from(transaction_id),
+            order_reference,
+            created_at: U64::from(u128::from(self.transaction_id) as u64),
+            product_id: self.product_id,
+            store_contract_id: self.store_contract_id,
+            buyer_id: self.buyer_id,
+            buyer_value_locked: self.buyer_value_locked,
+            price: self.price,
+            token_id: self.token_id,
+            timeout: self.timeout,
+            affiliate: self.affiliate,
+            affiliate_id: self.affiliate_id,
+            affiliate_percentage: self.affiliate_percentage,
+            is_discount: self.is_discount,
+            is_reward: self.is_reward,
+            is_keypom: self.is_keypom,
+            status: self.status,
+            hashed_billing_address: self.hashed_billing_address,
+            nonce: self.nonce,
+            ipfs: self.ipfs,
+            dispute_resolution: None,
+            cancellation_requested: false,
+            ft_token_id: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransactionFilter {
+    pub status: Option<TransactionStatus>,
+    pub from_timestamp: Option<U64>,
+    pub to_timestamp: Option<U64>,
+    pub product_id: Option<U64>,
+    pub is_keypom: Option<bool>,
+    pub is_discount: Option<bool>,
+    pub is_reward: Option<bool>,
+}
+
+impl TransactionFilter {
+    pub fn matches(&self, t: &Transaction) -> bool {
+        let timestamp: u64 = t.created_at.into();
+        self.status.as_ref().is_none_or(|status| &t.status == status)
+            && self.from_timestamp.is_none_or(|from| timestamp >= from.into())
+            && self.to_timestamp.is_none_or(|to| timestamp <= to.into())
+            && self.product_id.is_none_or(|product_id| t.product_id == product_id)
+            && self.is_keypom.is_none_or(|is_keypom| t.is_keypom == is_keypom)
+            && self.is_discount.is_none_or(|is_discount| t.is_discount == is_discount)
+            && self.is_reward.is_none_or(|is_reward| t.is_reward == is_reward)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    // Index to pass as `from_index` for the next page, or `None` once every order was seen.
+    pub next_index: Option<U128>,
 }
 
 #[near_bindgen]
@@ -75,6 +811,70 @@ pub struct Buy {
     attached_deposit: U128,
     color: String,
     affiliate: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ft_token_id: Option<AccountId>,
+    quantity: u32,
+}
+
+#[near_bindgen]
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtPurchaseMsg {
+    product_id: U64,
+    store_contract_id: AccountId,
+    color: String,
+    timeout: U128,
+    is_discount: bool,
+    is_reward: bool,
+    hashed_billing_address: String,
+    nonce: String,
+    affiliate: Option<AccountId>,
+    referrer: Option<AccountId>,
+    #[serde(default)]
+    quantity: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CartLine {
+    store_contract_id: AccountId,
+    product_id: U64,
+    color: String,
+    quantity: u32,
+    amount: U128,
+    is_discount: bool,
+    is_reward: bool,
+    affiliate: Option<AccountId>,
+}
+
+// Everything needed to record an order once the store has minted its tokens.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingPurchase {
+    buyer_id: AccountId,
+    amount: U128,
+    product_id: U64,
+    store_contract_id: AccountId,
+    timeout: U128,
+    is_discount: bool,
+    is_reward: bool,
+    is_keypom: bool,
+    hashed_billing_address: String,
+    nonce: String,
+    ft_token_id: Option<AccountId>,
+    referrer_id: Option<AccountId>,
+    cart_id: Option<U64>,
+    quantity: u32,
+    // Storage paid out of the attached deposit instead of a storage balance.
+    storage_deposit: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtTransfer {
+    receiver_id: AccountId,
+    amount: U128,
+    memo: Option<String>,
 }
 
 
@@ -129,6 +929,13 @@ pub struct Token {
     token_id: String,
 }
 
+#[near_bindgen]
+#[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Beneficiary {
+    beneficiary_id: AccountId,
+}
+
 #[near_bindgen]
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug, Clone)]
 #[serde(crate = "near_sdk::serde")]
@@ -140,13 +947,94 @@ pub struct MarketplaceData {
     token_id: String,
     token_owner: AccountId,
     store_owner: AccountId,
+    #[serde(default)]
+    royalty: HashMap<AccountId, u32>,
+    #[serde(default)]
+    token_ids: Vec<String>,
+}
+
+impl MarketplaceData {
+    // Stores that mint a single token only report `token_id`.
+    pub fn minted_token_ids(&self) -> Vec<String> {
+        if self.token_ids.is_empty() {
+            vec![self.token_id.clone()]
+        } else {
+            self.token_ids.clone()
+        }
+    }
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct PiparContractFactory {
     pub stores: LookupSet<String>,
-    pub transactions: Vector<Transaction>,
+    pub transactions: UnorderedMap<u128, VersionedTransaction>,
+    pub store_cost: U128,
+    pub stores_stats: UnorderedMap<AccountId, StoreStats>,
+    pub transactions_by_buyer: LookupMap<AccountId, UnorderedSet<u128>>,
+    pub transactions_by_store: LookupMap<AccountId, UnorderedSet<u128>>,
+    pub open_purchases: LookupMap<String, u128>,
+    pub order_references: LookupMap<String, u128>,
+    pub next_transaction_id: u128,
+    pub accepted_tokens: UnorderedSet<AccountId>,
+    pub owner_id: AccountId,
+    pub roles: LookupMap<Role, UnorderedSet<AccountId>>,
+    pub paused: bool,
+    pub store_code_versions: UnorderedMap<String, StoreCodeVersion>,
+    pub store_code: LookupMap<String, Vec<u8>>,
+    pub default_store_code_hash: String,
+    pub bundled_store_code_hash: String,
+    pub confirmation_window_days: u64,
+    pub platform_fee_bps: u16,
+    pub category_fees: UnorderedMap<String, u16>,
+    pub fee_balances: UnorderedMap<Option<AccountId>, u128>,
+    pub treasury_id: AccountId,
+    pub referral_fee_bps: u16,
+    pub store_registry: UnorderedMap<AccountId, StoreRecord>,
+    pub stores_by_owner: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    pub reserved_names: UnorderedSet<String>,
+    pub archived_stores: UnorderedMap<AccountId, Vec<ArchivedStore>>,
+    pub released_prefixes: LookupMap<String, u64>,
+    pub store_name_cooldown_days: u64,
+    pub keypom_contracts: UnorderedSet<AccountId>,
+    pub storage_accounts: LookupMap<AccountId, StorageAccount>,
+    pub store_creation_fee: U128,
+    pub next_cart_id: u64,
+    pub cart_transactions: LookupMap<u64, Vec<U128>>,
+    pub return_policies: LookupMap<AccountId, ReturnPolicy>,
+    pub tracking: LookupMap<u128, Vector<TrackingUpdate>>,
+    pub reviews: LookupMap<u128, Review>,
+    pub store_reviews: LookupMap<AccountId, Vector<u128>>,
+    pub store_reputations: LookupMap<AccountId, StoreReputation>,
+    pub claimable_balances: LookupMap<(AccountId, AccountId), u128>,
+    pub open_escrow_counts: LookupMap<AccountId, u64>,
+    pub pending_migration: Option<PendingMigration>,
+    pub locked_escrow: u128,
+    pub archived_store_transactions: LookupMap<(AccountId, u32), UnorderedSet<u128>>,
+    pub archived_store_reviews: LookupMap<(AccountId, u32), Vector<u128>>,
+}
+
+#[derive(BorshDeserialize)]
+pub struct PiparContractFactoryV1 {
+    pub stores: LookupSet<String>,
+    pub transactions: UnorderedMap<u128, VersionedTransaction>,
+    pub store_cost: U128,
+    pub stores_stats: UnorderedMap<AccountId, U128>,
+    pub transactions_by_buyer: LookupMap<AccountId, UnorderedSet<u128>>,
+    pub transactions_by_store: LookupMap<AccountId, UnorderedSet<u128>>,
+    pub open_purchases: LookupMap<String, u128>,
+    pub order_references: LookupMap<String, u128>,
+    pub next_transaction_id: u128,
+    pub accepted_tokens: UnorderedSet<AccountId>,
+    pub owner_id: AccountId,
+    pub roles: LookupMap<Role, UnorderedSet<AccountId>>,
+    pub paused: bool,
+}
+
+#[derive(BorshDeserialize)]
+pub struct LegacyPiparContractFactory {
+    pub stores: LookupSet<String>,
+    pub transactions: Vector<LegacyTransaction>,
     pub store_cost: U128,
     pub stores_stats: UnorderedMap<AccountId, U128>,
 }
@@ -155,14 +1043,7 @@ pub struct PiparContractFactory {
 impl PiparContractFactory {
 
     pub fn assert_no_store_with_id(&self, prefix: String) -> bool {
-        let current_account = env::current_account_id().to_string();
-        let account: AccountId = format!("{prefix}.{current_account}").parse().unwrap();
-
-        return if !self.check_contains_store(prefix) && env::is_valid_account_id(account.as_bytes()) {
-            true
-        } else {
-            false
-        }
+        self.internal_check_store_prefix(&prefix).is_ok()
     }
 
     pub fn assert_only_buyer(&self, buyer_account_id: AccountId) {
@@ -181,6 +1062,30 @@ impl PiparContractFactory {
         )
     }
 
+    pub fn assert_only_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the contract owner can call this method"
+        )
+    }
+
+    pub fn assert_role(&self, role: Role) {
+        assert!(
+            self.has_role(env::predecessor_account_id(), role),
+            "Only an account with the {:?} role can call this method",
+            role
+        )
+    }
+
+    pub fn assert_only_arbiter(&self) {
+        self.assert_role(Role::Arbiter)
+    }
+
+    pub fn assert_not_paused(&self) {
+        assert!(!self.paused, "The marketplace is paused, please try again later")
+    }
+
     pub fn calculate_timeout(&self, timeout: U128, timestamp: U128) -> u128 {
         let timeout: u128 = timeout.into();
         let timestamp: u128 = timestamp.into();
@@ -195,42 +1100,748 @@ impl PiparContractFactory {
         self.stores.contains(&store_id)
     }
 
-    pub fn get_store_cost(&self) -> u128 {
-        self.store_cost.into()
+    pub fn is_arbiter(&self, account_id: AccountId) -> bool {
+        self.has_role(account_id, Role::Arbiter)
     }
 
-    pub fn get_transaction_count(&self) -> usize {
-        self.transactions.iter().count()
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
     }
 
-    pub fn get_all_transactions(&self) -> Vec<Transaction> {
-        let transactions: Vec<Transaction> = self.transactions.iter().map(|x| x).collect();
-
-        transactions
+    pub fn is_paused(&self) -> bool {
+        self.paused
     }
 
-    pub fn get_buyer_transactions(&self, account_id: AccountId) -> Vec<Transaction> {
-        let transactions: Vec<Transaction> = self.transactions.iter().filter(|x| x.buyer_id == account_id).collect();
-
-        transactions
+    pub fn has_role(&self, account_id: AccountId, role: Role) -> bool {
+        if account_id == self.owner_id {
+            return true;
+        }
+        match self.roles.get(&role) {
+            Some(members) => members.contains(&account_id),
+            None => false,
+        }
     }
 
-    pub fn get_seller_transactions(&self, account_id: AccountId) -> Vec<Transaction> {
-        let transactions: Vec<Transaction> = self.transactions.iter().filter(|x| x.store_contract_id == account_id).collect();
+    pub fn get_role_members(&self, role: Role, from_index: Option<U128>, limit: Option<u64>) -> Vec<AccountId> {
+        match self.roles.get(&role) {
+            Some(members) => members
+                .iter()
+                .skip(u128::from(from_index.unwrap_or(U128(0))) as usize)
+                .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_accepted_tokens(&self) -> Vec<AccountId> {
+        self.accepted_tokens.to_vec()
+    }
+
+    pub fn get_keypom_contracts(&self) -> Vec<AccountId> {
+        self.keypom_contracts.to_vec()
+    }
+
+    pub fn get_reserved_names(&self) -> Vec<String> {
+        self.reserved_names.to_vec()
+    }
+
+    pub fn check_store_prefix(&self, prefix: String) -> Option<String> {
+        self.internal_check_store_prefix(&prefix).err()
+    }
+
+    pub fn get_store_code_versions(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<StoreCodeVersion> {
+        self.store_code_versions
+            .values()
+            .skip(u128::from(from_index.unwrap_or(U128(0))) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+            .collect()
+    }
+
+    pub fn get_store_code_version(&self, code_hash: String) -> Option<StoreCodeVersion> {
+        self.store_code_versions.get(&code_hash)
+    }
+
+    pub fn get_default_store_code_hash(&self) -> String {
+        self.default_store_code_hash.clone()
+    }
+
+    pub fn get_store_code_hash(&self, store_id: AccountId) -> Option<String> {
+        self.store_registry.get(&store_id).map(|store| store.code_hash)
+    }
+
+    pub fn get_archived_store(&self, store_id: AccountId) -> Option<ArchivedStore> {
+        self.archived_stores.get(&store_id).and_then(|history| history.last().cloned())
+    }
+
+    // Every time a store under this name was closed, oldest first.
+    pub fn get_archived_store_history(&self, store_id: AccountId) -> Vec<ArchivedStore> {
+        self.archived_stores.get(&store_id).unwrap_or_default()
+    }
+
+    pub fn get_store_name_cooldown_days(&self) -> u64 {
+        self.store_name_cooldown_days
+    }
+
+    pub fn get_confirmation_window_days(&self) -> u64 {
+        self.confirmation_window_days
+    }
+
+    pub fn get_platform_fee_bps(&self, category: Option<String>) -> u16 {
+        category
+            .and_then(|category| self.category_fees.get(&category))
+            .unwrap_or(self.platform_fee_bps)
+    }
+
+    pub fn get_category_fees(&self) -> Vec<(String, u16)> {
+        self.category_fees.to_vec()
+    }
+
+    pub fn get_treasury(&self) -> AccountId {
+        self.treasury_id.clone()
+    }
+
+    pub fn get_fee_balance(&self, ft_token_id: Option<AccountId>) -> U128 {
+        U128::from(self.fee_balances.get(&ft_token_id).unwrap_or(0))
+    }
+
+    pub fn get_fee_balances(&self) -> Vec<(Option<AccountId>, U128)> {
+        self.fee_balances
+            .iter()
+            .map(|(ft_token_id, balance)| (ft_token_id, U128::from(balance)))
+            .collect()
+    }
+
+    pub fn get_claimable_balance(&self, account_id: AccountId, ft_token_id: AccountId) -> U128 {
+        U128::from(self.claimable_balances.get(&(account_id, ft_token_id)).unwrap_or(0))
+    }
+
+    pub fn get_referral_fee_bps(&self) -> u16 {
+        self.referral_fee_bps
+    }
+
+    pub fn get_payout_preview(&self, transaction_id: U128) -> Vec<Payout> {
+        let t = self.internal_get_transaction(transaction_id.into()).expect("Transaction not found");
+        self.internal_release_payouts(&t, t.buyer_value_locked.into())
+    }
+
+    pub fn get_store_stats(&self, store_id: AccountId) -> StoreStats {
+        self.stores_stats.get(&store_id).unwrap_or_default()
+    }
+
+    pub fn get_all_store_stats(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<(AccountId, StoreStats)> {
+        self.stores_stats
+            .iter()
+            .skip(u128::from(from_index.unwrap_or(U128(0))) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+            .collect()
+    }
+
+    pub fn get_store(&self, store_id: AccountId) -> Option<StoreRecord> {
+        self.store_registry.get(&store_id)
+    }
+
+    pub fn get_store_count(&self) -> u64 {
+        self.store_registry.len()
+    }
+
+    pub fn list_stores(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<StoreRecord> {
+        self.store_registry
+            .values()
+            .skip(u128::from(from_index.unwrap_or(U128(0))) as usize)
+            .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+            .collect()
+    }
+
+    pub fn list_stores_by_owner(
+        &self,
+        owner_id: AccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<StoreRecord> {
+        match self.stores_by_owner.get(&owner_id) {
+            Some(store_ids) => store_ids
+                .iter()
+                .skip(u128::from(from_index.unwrap_or(U128(0))) as usize)
+                .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+                .filter_map(|store_id| self.store_registry.get(&store_id))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_store_stats_count(&self) -> u64 {
+        self.stores_stats.len()
+    }
+
+    pub fn get_store_cost(&self) -> u128 {
+        self.store_cost.into()
+    }
+
+    pub fn quote_store_creation(&self) -> StoreCreationQuote {
+        let store_balance: u128 = self.store_cost.into();
+        let registry_storage = STORE_REGISTRY_STORAGE_BYTES as u128 * env::storage_byte_cost();
+        let creation_fee: u128 = self.store_creation_fee.into();
+        StoreCreationQuote {
+            store_balance: U128::from(store_balance),
+            registry_storage: U128::from(registry_storage),
+            creation_fee: U128::from(creation_fee),
+            total: U128::from(store_balance + registry_storage + creation_fee),
+        }
+    }
+
+    pub fn get_transaction_count(&self) -> usize {
+        self.transactions.len() as usize
+    }
+
+    pub fn get_buyer_transaction_count(&self, account_id: AccountId) -> u64 {
+        self.transactions_by_buyer.get(&account_id).map_or(0, |ids| ids.len())
+    }
+
+    pub fn get_seller_transaction_count(&self, account_id: AccountId) -> u64 {
+        self.transactions_by_store.get(&account_id).map_or(0, |ids| ids.len())
+    }
+
+    pub fn get_open_escrow_count(&self, store_id: AccountId) -> u64 {
+        self.open_escrow_counts.get(&store_id).unwrap_or(0)
+    }
+
+    pub fn get_locked_escrow(&self) -> U128 {
+        U128::from(self.locked_escrow)
+    }
+
+    pub fn get_transaction(&self, transaction_id: U128) -> Option<Transaction> {
+        self.internal_get_transaction(transaction_id.into())
+    }
+
+    pub fn get_cart_transactions(&self, cart_id: U64) -> Vec<Transaction> {
+        self.cart_transactions
+            .get(&u64::from(cart_id))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|transaction_id| self.internal_get_transaction(transaction_id.into()))
+            .collect()
+    }
+
+    pub fn get_transaction_by_reference(&self, order_reference: String) -> Option<Transaction> {
+        self.order_references
+            .get(&order_reference)
+            .and_then(|transaction_id| self.internal_get_transaction(transaction_id))
+    }
+
+    pub fn get_all_transactions(
+        &self,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+        filter: Option<TransactionFilter>,
+    ) -> TransactionPage {
+        self.paginate_transactions(self.transactions.keys(), from_index, limit, filter)
+    }
+
+    pub fn get_buyer_transactions(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+        filter: Option<TransactionFilter>,
+    ) -> TransactionPage {
+        match self.transactions_by_buyer.get(&account_id) {
+            Some(ids) => self.paginate_transactions(ids.iter(), from_index, limit, filter),
+            None => TransactionPage { transactions: vec![], next_index: None },
+        }
+    }
 
-        transactions
+    pub fn get_seller_transactions(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+        filter: Option<TransactionFilter>,
+    ) -> TransactionPage {
+        match self.transactions_by_store.get(&account_id) {
+            Some(ids) => self.paginate_transactions(ids.iter(), from_index, limit, filter),
+            None => TransactionPage { transactions: vec![], next_index: None },
+        }
     }
 
     #[init]
-    pub fn new() -> Self {
-        Self {
-            stores: LookupSet::new(b"s".to_vec()),
-            transactions: Vector::new(b"v".to_vec()),
-            store_cost: U128::from(STORE_BALANCE),
-            stores_stats: UnorderedMap::new(b"w".to_vec()),
+    pub fn new(owner_id: Option<AccountId>) -> Self {
+        let owner_id = owner_id.unwrap_or_else(env::predecessor_account_id);
+        Self::internal_write_state_version();
+        let mut contract = Self::internal_default_state(owner_id);
+        contract.internal_register_defaults();
+
+        contract
+    }
+
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let from_version = env::storage_read(STATE_VERSION_KEY)
+            .map(|bytes| StateVersion::try_from_slice(&bytes).expect("Failed to read state version"));
+        let contract = match from_version {
+            None => Self::internal_migrate_legacy_state(),
+            Some(StateVersion::V1) => Self::internal_migrate_v1_state(),
+            Some(StateVersion::V2) => env::state_read().expect("Failed to read contract state"),
+        };
+        Self::internal_write_state_version();
+        emit_event("state_migrated", json!({
+            "from_version": from_version,
+            "to_version": CURRENT_STATE_VERSION,
+        }));
+
+        contract
+    }
+
+    pub fn upgrade(&self) {
+        self.assert_only_owner();
+        let code = env::input().expect("Contract code is required");
+        // Hashed before the gas is measured so the hashing is already paid for.
+        let code_hash = hex_encode(&env::sha256(&code));
+        let migrate_gas = upgrade_migrate_gas(env::prepaid_gas(), env::used_gas(), code.len())
+            .unwrap_or_else(|| env::panic_str(&format!(
+                "Not enough gas to deploy {} bytes of code and migrate, attach more than {} gas",
+                code.len(),
+                u64::from(env::used_gas() + deploy_gas(code.len()) + MIGRATE_GAS_RESERVE)
+            )));
+        emit_event("contract_upgraded", json!({
+            "code_hash": code_hash,
+            "upgraded_by": env::predecessor_account_id(),
+        }));
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_owned(), Vec::new(), NO_DEPOSIT, migrate_gas)
+            .as_return();
+    }
+
+    // Moves up to `limit` of the orders `migrate` left behind into the current layout and
+    // returns how many are still pending.
+    pub fn migrate_transactions(&mut self, limit: Option<u64>) -> U64 {
+        self.assert_only_owner();
+        let pending = self.pending_migration.take().expect("There is no migration in progress");
+        let limit = limit.unwrap_or(DEFAULT_MIGRATION_BATCH);
+        let (pending, remaining) = match pending {
+            PendingMigration::Legacy { mut transactions } => {
+                for _ in 0..limit.min(transactions.len()) {
+                    let transaction_id = transactions.len() as u128;
+                    let legacy = transactions.pop().unwrap();
+                    let t = legacy.into_v1(transaction_id, self.internal_order_reference(transaction_id));
+                    let t = self.internal_migrated_transaction(t);
+                    self.internal_add_transaction(&t);
+                }
+                let remaining = transactions.len();
+                (PendingMigration::Legacy { transactions }, remaining)
+            }
+            PendingMigration::V1 { next_index, end_index } => {
+                let last_index = end_index.min(next_index + limit);
+                for index in next_index..last_index {
+                    let transaction_id = self.transactions.keys_as_vector().get(index).unwrap();
+                    // Orders updated since the upgrade were already counted when they were rewritten.
+                    if let Some(VersionedTransaction::V1(t)) = self.transactions.get(&transaction_id) {
+                        let t = self.internal_migrated_transaction(*t);
+                        self.transactions.insert(&transaction_id, &VersionedTransaction::from(t.clone()));
+                        self.internal_count_transaction(&t);
+                    }
+                }
+                (PendingMigration::V1 { next_index: last_index, end_index }, end_index - last_index)
+            }
+        };
+        if remaining > 0 {
+            self.pending_migration = Some(pending);
+        }
+        emit_event("transactions_migrated", json!({ "remaining": U64::from(remaining) }));
+        U64::from(remaining)
+    }
+
+    pub fn get_pending_migration_count(&self) -> U64 {
+        match &self.pending_migration {
+            Some(PendingMigration::Legacy { transactions }) => U64::from(transactions.len()),
+            Some(PendingMigration::V1 { next_index, end_index }) => U64::from(end_index - next_index),
+            None => U64::from(0),
+        }
+    }
+
+    pub fn get_state_version(&self) -> StateVersion {
+        CURRENT_STATE_VERSION
+    }
+
+    pub fn get_contract_version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_owned()
+    }
+
+    #[payable]
+    pub fn set_owner(&mut self, owner_id: AccountId) {
+        assert_one_yocto();
+        self.assert_only_owner();
+        let old_owner_id = std::mem::replace(&mut self.owner_id, owner_id);
+        emit_event("owner_changed", json!({ "old_owner_id": old_owner_id, "new_owner_id": self.owner_id }));
+    }
+
+    #[payable]
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.internal_assert_can_manage_role(role);
+        let mut members = self.roles.get(&role).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::RoleMembers { role })
+        });
+        assert!(members.insert(&account_id), "{} already has the {:?} role", account_id, role);
+        self.roles.insert(&role, &members);
+        emit_event("role_granted", json!({
+            "account_id": account_id,
+            "role": role,
+            "granted_by": env::predecessor_account_id(),
+        }));
+    }
+
+    #[payable]
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        assert_one_yocto();
+        self.internal_assert_can_manage_role(role);
+        let mut members = self.roles.get(&role).expect("Role has no members");
+        assert!(members.remove(&account_id), "{} does not have the {:?} role", account_id, role);
+        self.roles.insert(&role, &members);
+        emit_event("role_revoked", json!({
+            "account_id": account_id,
+            "role": role,
+            "revoked_by": env::predecessor_account_id(),
+        }));
+    }
+
+    pub fn renounce_role(&mut self, role: Role) {
+        let account_id = env::predecessor_account_id();
+        let mut members = self.roles.get(&role).expect("Role has no members");
+        assert!(members.remove(&account_id), "{} does not have the {:?} role", account_id, role);
+        self.roles.insert(&role, &members);
+        emit_event("role_revoked", json!({
+            "account_id": account_id,
+            "role": role,
+            "revoked_by": account_id,
+        }));
+    }
+
+    pub fn pause(&mut self) {
+        self.assert_role(Role::Operator);
+        self.paused = true;
+        emit_event("marketplace_paused", json!({ "paused_by": env::predecessor_account_id() }));
+    }
+
+    pub fn unpause(&mut self) {
+        self.assert_role(Role::Admin);
+        self.paused = false;
+        emit_event("marketplace_unpaused", json!({ "unpaused_by": env::predecessor_account_id() }));
+    }
+
+    pub fn set_store_cost(&mut self, store_cost: U128) {
+        self.assert_role(Role::Admin);
+        self.store_cost = store_cost;
+        emit_event("store_cost_updated", json!({ "store_cost": store_cost }));
+    }
+
+    pub fn set_store_creation_fee(&mut self, fee: U128) {
+        self.assert_role(Role::Admin);
+        self.store_creation_fee = fee;
+        emit_event("store_creation_fee_updated", json!({ "fee": fee }));
+    }
+
+    #[payable]
+    pub fn store_code(&mut self) -> String {
+        self.assert_role(Role::Admin);
+        let code = env::input().expect("Store code is required");
+        let code_hash = hex_encode(&env::sha256(&code));
+        assert!(
+            self.store_code_versions.get(&code_hash).is_none(),
+            "Store code {} is already registered",
+            code_hash
+        );
+
+        let entry_points = Self::store_entry_points(&code);
+
+        let initial_storage = env::storage_usage();
+        self.store_code.insert(&code_hash, &code);
+        self.store_code_versions.insert(&code_hash, &StoreCodeVersion {
+            version: self.store_code_versions.len() as u32 + 1,
+            code_hash: code_hash.clone(),
+            release_notes: String::new(),
+            is_default: false,
+            created_at: U64::from(env::block_timestamp()),
+            entry_points,
+        });
+        let storage_cost = (env::storage_usage() - initial_storage) as u128 * env::storage_byte_cost();
+        assert!(
+            env::attached_deposit() >= storage_cost,
+            "To cover the storage required for the store code, you need to attach at least {} yoctoNEAR to this transaction.",
+            storage_cost
+        );
+        let refund = env::attached_deposit() - storage_cost;
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+        emit_event("store_code_added", json!({ "code_hash": code_hash }));
+
+        code_hash
+    }
+
+    pub fn set_store_code_metadata(&mut self, code_hash: String, release_notes: String, set_default: bool) {
+        self.assert_role(Role::Admin);
+        let mut version = self.store_code_versions.get(&code_hash).expect("Store code not found");
+        version.release_notes = release_notes;
+        self.store_code_versions.insert(&code_hash, &version);
+        if set_default {
+            self.internal_set_default_store_code(&code_hash);
+        }
+    }
+
+    pub fn set_default_store_code(&mut self, code_hash: String) {
+        self.assert_role(Role::Admin);
+        assert!(self.store_code_versions.get(&code_hash).is_some(), "Store code not found");
+        self.internal_set_default_store_code(&code_hash);
+    }
+
+    pub fn upgrade_store(&mut self, prefix: String, code_hash: Option<String>) -> Promise {
+        assert!(self.stores.contains(&prefix), "Store {} not found", prefix);
+        let code_hash = code_hash.unwrap_or_else(|| self.default_store_code_hash.clone());
+        assert!(self.store_code_versions.get(&code_hash).is_some(), "Store code not found");
+        let store_id: AccountId = format!("{}.{}", prefix, env::current_account_id()).parse().unwrap();
+        self.internal_assert_store_entry_point(&store_id, "upgrade");
+
+        if self.has_role(env::predecessor_account_id(), Role::Admin) {
+            self.internal_upgrade_store(&prefix, &code_hash, self.internal_store_code(&code_hash))
+        } else {
+            let args = serde_json::to_vec(&EmptyData {})
+                .unwrap();
+            Promise::new(store_id)
+                .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .upgrade_store_owner_callback(prefix, code_hash),
+                )
+        }
+    }
+
+    pub fn upgrade_stores(&mut self, prefixes: Vec<String>, code_hash: Option<String>) {
+        self.assert_role(Role::Admin);
+        let code_hash = code_hash.unwrap_or_else(|| self.default_store_code_hash.clone());
+        assert!(self.store_code_versions.get(&code_hash).is_some(), "Store code not found");
+        assert!(
+            !prefixes.is_empty() && prefixes.len() <= MAX_STORE_UPGRADE_BATCH,
+            "Stores are upgraded in batches of 1 to {}",
+            MAX_STORE_UPGRADE_BATCH
+        );
+        let code = self.internal_store_code(&code_hash);
+        let required_gas = store_upgrade_batch_gas(code.len(), prefixes.len());
+        assert!(
+            env::prepaid_gas().0.saturating_sub(env::used_gas().0) >= required_gas.0,
+            "Upgrading {} stores requires at least {} gas",
+            prefixes.len(),
+            required_gas.0
+        );
+        for prefix in prefixes {
+            assert!(self.stores.contains(&prefix), "Store {} not found", prefix);
+            let store_id: AccountId = format!("{}.{}", prefix, env::current_account_id()).parse().unwrap();
+            self.internal_assert_store_entry_point(&store_id, "upgrade");
+            self.internal_upgrade_store(&prefix, &code_hash, code.clone());
+        }
+    }
+
+    // Stores deployed before the registry existed only have their prefix recorded, so a
+    // verifier confirms who owns them before they are registered.
+    pub fn import_store(&mut self, store: StoreRecord) {
+        self.assert_role(Role::Verifier);
+        assert!(self.stores.contains(&store.prefix), "Store {} not found", store.prefix);
+        assert_eq!(
+            store.store_id.to_string(),
+            format!("{}.{}", store.prefix, env::current_account_id()),
+            "Store account does not match its prefix"
+        );
+        assert!(self.store_registry.get(&store.store_id).is_none(), "Store is already registered");
+        let store_id = store.store_id.clone();
+        let owner_id = store.owner_id.clone();
+        self.internal_add_store(store);
+        emit_event("store_imported", json!({ "store_id": store_id, "owner_id": owner_id }));
+    }
+
+    #[private]
+    pub fn upgrade_store_owner_callback(&mut self, prefix: String, code_hash: String) -> Promise {
+        Self::internal_assert_store_owner_result("Only the store owner can upgrade this store");
+        self.internal_upgrade_store(&prefix, &code_hash, self.internal_store_code(&code_hash))
+    }
+
+    #[private]
+    pub fn upgrade_store_callback(&mut self, store_id: AccountId, code_hash: String) {
+        if is_promise_success() {
+            if let Some(mut store) = self.store_registry.get(&store_id) {
+                store.code_hash = code_hash.clone();
+                self.store_registry.insert(&store_id, &store);
+            }
+            emit_event("store_upgraded", json!({ "store_id": store_id, "code_hash": code_hash }));
+            env::log_str("Successful store upgrade")
+        } else {
+            emit_event("store_upgrade_failed", json!({ "store_id": store_id, "code_hash": code_hash }));
+            env::log_str("failed store upgrade")
+        }
+    }
+
+    pub fn set_confirmation_window_days(&mut self, days: u64) {
+        self.assert_role(Role::Admin);
+        assert!(days > 0, "Confirmation window must be at least one day");
+        self.confirmation_window_days = days;
+        emit_event("confirmation_window_updated", json!({ "days": days }));
+    }
+
+    pub fn set_store_name_cooldown_days(&mut self, days: u64) {
+        self.assert_role(Role::Admin);
+        self.store_name_cooldown_days = days;
+        emit_event("store_name_cooldown_updated", json!({ "days": days }));
+    }
+
+    pub fn set_platform_fee(&mut self, fee_bps: u16) {
+        self.assert_role(Role::Admin);
+        assert!(fee_bps <= MAX_PLATFORM_FEE_BPS, "Platform fee cannot exceed {} basis points", MAX_PLATFORM_FEE_BPS);
+        self.platform_fee_bps = fee_bps;
+        emit_event("platform_fee_updated", json!({ "fee_bps": fee_bps }));
+    }
+
+    pub fn set_category_fee(&mut self, category: String, fee_bps: Option<u16>) {
+        self.assert_role(Role::Admin);
+        match fee_bps {
+            Some(fee_bps) => {
+                assert!(fee_bps <= MAX_PLATFORM_FEE_BPS, "Platform fee cannot exceed {} basis points", MAX_PLATFORM_FEE_BPS);
+                self.category_fees.insert(&category, &fee_bps);
+            }
+            None => {
+                self.category_fees.remove(&category);
+            }
+        }
+        emit_event("category_fee_updated", json!({ "category": category, "fee_bps": fee_bps }));
+    }
+
+    pub fn set_referral_fee(&mut self, fee_bps: u16) {
+        self.assert_role(Role::Admin);
+        assert!(fee_bps as u128 <= BASIS_POINTS, "Referral fee cannot exceed {} basis points", BASIS_POINTS);
+        self.referral_fee_bps = fee_bps;
+        emit_event("referral_fee_updated", json!({ "fee_bps": fee_bps }));
+    }
+
+    #[payable]
+    pub fn set_treasury(&mut self, treasury_id: AccountId) {
+        assert_one_yocto();
+        self.assert_only_owner();
+        self.treasury_id = treasury_id;
+        emit_event("treasury_updated", json!({ "treasury_id": self.treasury_id }));
+    }
+
+    pub fn withdraw_fees(&mut self, amount: Option<U128>, ft_token_id: Option<AccountId>) -> Promise {
+        self.assert_role(Role::Admin);
+        let balance = self.fee_balances.get(&ft_token_id).unwrap_or(0);
+        let amount: u128 = amount.map(u128::from).unwrap_or(balance);
+        assert!(amount > 0, "There are no fees to withdraw");
+        assert!(amount <= balance, "Cannot withdraw more than the {} collected in fees", balance);
+        if ft_token_id.is_none() {
+            // Legacy orders are only counted towards the locked escrow once they are migrated.
+            assert!(self.pending_migration.is_none(), "Orders are still being migrated, please try again later");
+            let storage_cost = env::storage_usage() as u128 * env::storage_byte_cost();
+            assert!(
+                env::account_balance().saturating_sub(amount) >= storage_cost + self.locked_escrow,
+                "Withdrawal would leave the factory unable to cover its storage and the escrow it holds"
+            );
+        }
+
+        self.fee_balances.insert(&ft_token_id, &(balance - amount));
+        self.internal_transfer(&self.treasury_id, amount, &ft_token_id)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(5 * TGAS))
+                    .withdraw_fees_callback(U128::from(amount), ft_token_id),
+            )
+    }
+
+    #[private]
+    pub fn withdraw_fees_callback(&mut self, amount: U128, ft_token_id: Option<AccountId>) {
+        let amount: u128 = amount.into();
+        if is_promise_success() {
+            emit_payout_event(None, &self.treasury_id, amount, "platform_fee");
+        } else {
+            let balance = self.fee_balances.get(&ft_token_id).unwrap_or(0);
+            self.fee_balances.insert(&ft_token_id, &(balance + amount));
+            env::log_str("failed fee withdrawal & fees returned to the balance")
+        }
+    }
+
+    // Token payouts that failed, e.g. because the receiver was not registered with the
+    // token contract, are kept here until the receiver claims them.
+    pub fn claim_payout(&mut self, ft_token_id: AccountId) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let key = (account_id.clone(), ft_token_id.clone());
+        let amount = self.claimable_balances.get(&key).unwrap_or(0);
+        assert!(amount > 0, "There is nothing to claim");
+        self.claimable_balances.remove(&key);
+        self.internal_ft_payout(&account_id, amount, &ft_token_id, None, "claim")
+    }
+
+    #[private]
+    pub fn resolve_ft_payout_callback(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        ft_token_id: AccountId,
+        transaction_id: Option<U128>,
+        kind: String,
+    ) {
+        let amount: u128 = amount.into();
+        if is_promise_success() {
+            emit_payout_event(transaction_id, &receiver_id, amount, &kind);
+        } else {
+            let key = (receiver_id.clone(), ft_token_id.clone());
+            let balance = self.claimable_balances.get(&key).unwrap_or(0);
+            self.claimable_balances.insert(&key, &(balance + amount));
+            emit_event("payout_failed", json!({
+                "transaction_id": transaction_id,
+                "receiver_id": receiver_id,
+                "ft_token_id": ft_token_id,
+                "amount": U128::from(amount),
+                "kind": kind,
+            }));
+            env::log_str("failed token payout & amount credited to the receiver's claimable balance")
         }
     }
 
+    pub fn add_accepted_token(&mut self, token_id: AccountId) {
+        self.assert_role(Role::Admin);
+        self.accepted_tokens.insert(&token_id);
+        emit_event("accepted_token_added", json!({ "token_id": token_id }));
+    }
+
+    pub fn remove_accepted_token(&mut self, token_id: AccountId) {
+        self.assert_role(Role::Admin);
+        self.accepted_tokens.remove(&token_id);
+        emit_event("accepted_token_removed", json!({ "token_id": token_id }));
+    }
+
+    pub fn add_keypom_contract(&mut self, contract_id: AccountId) {
+        self.assert_role(Role::Admin);
+        self.keypom_contracts.insert(&contract_id);
+        emit_event("keypom_contract_added", json!({ "contract_id": contract_id }));
+    }
+
+    pub fn remove_keypom_contract(&mut self, contract_id: AccountId) {
+        self.assert_role(Role::Admin);
+        self.keypom_contracts.remove(&contract_id);
+        emit_event("keypom_contract_removed", json!({ "contract_id": contract_id }));
+    }
+
+    pub fn add_reserved_name(&mut self, name: String) {
+        self.assert_role(Role::Admin);
+        let name = name.to_lowercase();
+        self.reserved_names.insert(&name);
+        emit_event("reserved_name_added", json!({ "name": name }));
+    }
+
+    pub fn remove_reserved_name(&mut self, name: String) {
+        self.assert_role(Role::Admin);
+        let name = name.to_lowercase();
+        assert!(self.reserved_names.remove(&name), "Name {} is not reserved", name);
+        emit_event("reserved_name_removed", json!({ "name": name }));
+    }
+
     #[payable]
     pub fn create_account(
         &mut self,
@@ -251,15 +1862,43 @@ impl PiparContractFactory {
         zip: String,
         city: String,
         state: String,
-        country: String
+        country: String,
+        funder_id: Option<AccountId>,
+        drop_id: Option<String>,
     ) -> Promise {
-        let prefix = &new_account_id[0..new_account_id.len() - 8];
-        let public_key: PublicKey = new_public_key;
-        let _keypom = keypom_args;
+        self.assert_not_paused();
+        assert!(
+            self.keypom_contracts.contains(&env::predecessor_account_id()),
+            "Only a registered Keypom contract can onboard stores"
+        );
+        // Keypom injects the new account, funder and drop into the fields named in
+        // keypom_args, so only values arriving through those fields can be trusted.
+        assert_eq!(
+            keypom_args.account_id_field.as_deref(),
+            Some("new_account_id"),
+            "Keypom must provide the new account through the new_account_id field"
+        );
+        assert_eq!(
+            keypom_args.funder_id_field.as_deref(),
+            Some("funder_id"),
+            "Keypom must provide the funder through the funder_id field"
+        );
+        let funder_id = funder_id.expect("Missing Keypom funder");
+        if let Some(field) = keypom_args.drop_id_field.as_deref() {
+            assert_eq!(field, "drop_id", "Keypom must provide the drop through the drop_id field");
+            assert!(drop_id.is_some(), "Missing Keypom drop");
+        }
+
+        let owner_id: AccountId = new_account_id.parse().expect("Invalid new account id");
+        let prefix = owner_id.as_str().split('.').next().unwrap().to_string();
+        if let Err(err) = self.internal_check_store_prefix(&prefix) {
+            env::panic_str(&err)
+        }
+        let quote = self.internal_assert_store_deposit();
         let current_account = env::current_account_id().to_string();
         let subaccount: AccountId = format!("{prefix}.{current_account}").parse().unwrap();
-        let init_args = serde_json::to_vec(&FtData {
-            owner_id: new_account_id.parse().unwrap(),
+        let ft_data = FtData {
+            owner_id: owner_id.clone(),
             marketplace_contract_id: env::current_account_id(),
             name,
             symbol,
@@ -276,28 +1915,65 @@ impl PiparContractFactory {
             city,
             state,
             country,
-        })
-        .unwrap();
+        };
+        let init_args = serde_json::to_vec(&ft_data).unwrap();
 
         Promise::new(subaccount.clone())
             .create_account()
-            .add_full_access_key(public_key)
-            .transfer(STORE_BALANCE)
-            .deploy_contract(include_bytes!("../wasm/store.wasm").to_vec())
+            .add_full_access_key(new_public_key)
+            .transfer(quote.store_balance.into())
+            .deploy_contract(self.internal_store_code(&self.default_store_code_hash))
             .function_call("new_default_meta".to_owned(), init_args, NO_DEPOSIT, PGAS)
             .then(
                 Self::ext(env::current_account_id())
                     .with_static_gas(Gas(5 * TGAS))
-                    .deploy_store_keypom_callback(prefix.to_string()),
+                    .deploy_store_keypom_callback(
+                        funder_id,
+                        drop_id,
+                        U128::from(env::attached_deposit()),
+                        quote,
+                        self.internal_store_record(&subaccount, &prefix, &ft_data, true),
+                    ),
             )
     }
 
     #[private]
-    pub fn deploy_store_keypom_callback(&mut self, prefix: String) {
+    pub fn deploy_store_keypom_callback(
+        &mut self,
+        funder_id: AccountId,
+        drop_id: Option<String>,
+        attached_deposit: U128,
+        quote: StoreCreationQuote,
+        store: StoreRecord,
+    ) {
+        let attached_deposit: u128 = attached_deposit.into();
+        let store_id = store.store_id.clone();
+        let prefix = store.prefix.clone();
+        let owner_id = store.owner_id.clone();
         if is_promise_success() {
-            self.stores.insert(&prefix);
+            let initial_storage = env::storage_usage();
+            self.internal_add_store(StoreRecord { created_at: U64::from(env::block_timestamp()), ..store });
+            self.internal_settle_store_creation(&funder_id, attached_deposit, &quote, initial_storage);
+            emit_event("store_created", json!({
+                "store_id": store_id,
+                "prefix": prefix,
+                "owner_id": owner_id,
+                "funder_id": funder_id,
+                "drop_id": drop_id,
+                "is_keypom": true,
+            }));
             env::log_str("Successful token deployment")
         } else {
+            Promise::new(funder_id.clone()).transfer(attached_deposit);
+            emit_event("store_creation_failed", json!({
+                "store_id": store_id,
+                "prefix": prefix,
+                "owner_id": owner_id,
+                "funder_id": funder_id,
+                "drop_id": drop_id,
+                "is_keypom": true,
+            }));
+            emit_payout_event(None, &funder_id, attached_deposit, "store_creation_refund");
             env::log_str("failed token deployment & funds returned")
         }
     }
@@ -308,13 +1984,31 @@ impl PiparContractFactory {
         store_creator_id: AccountId,
         prefix: String,
         attached_deposit: U128,
+        quote: StoreCreationQuote,
+        store: StoreRecord,
     ) {
         let attached_deposit: u128 = attached_deposit.into();
+        let store_id = store.store_id.clone();
         if is_promise_success() {
-            self.stores.insert(&prefix);
+            let initial_storage = env::storage_usage();
+            self.internal_add_store(StoreRecord { created_at: U64::from(env::block_timestamp()), ..store });
+            self.internal_settle_store_creation(&store_creator_id, attached_deposit, &quote, initial_storage);
+            emit_event("store_created", json!({
+                "store_id": store_id,
+                "prefix": prefix,
+                "owner_id": store_creator_id,
+                "is_keypom": false,
+            }));
             env::log_str("Successful store deployment")
         } else {
-            Promise::new(store_creator_id).transfer(attached_deposit);
+            Promise::new(store_creator_id.clone()).transfer(attached_deposit);
+            emit_event("store_creation_failed", json!({
+                "store_id": store_id,
+                "prefix": prefix,
+                "owner_id": store_creator_id,
+                "is_keypom": false,
+            }));
+            emit_payout_event(None, &store_creator_id, attached_deposit, "store_creation_refund");
             env::log_str("failed store deployment & funds returned")
         }
     }
@@ -335,20 +2029,14 @@ impl PiparContractFactory {
                         city: String,
                         state: String,
                         country: String) -> Promise {
-        assert!(
-            env::attached_deposit() > STORE_BALANCE,
-            "To cover the storage required for your store, you need to attach at least {} yoctoNEAR to this transaction.",
-            STORE_BALANCE
-        );
-        self.assert_no_store_with_id(prefix.clone());
-        self.assert_no_store_with_id(prefix.clone());
-        assert_ne!(prefix.clone(), "market", "cannot use name for store, choose another name");
-        assert_ne!(prefix.clone(), "pipar", "cannot use name for store, choose another name");
-        assert_ne!(prefix.clone(), "dao", "cannot use name for store, choose another name");
-        assert_ne!(prefix.clone(), "auction", "cannot use name for store, choose another name");
+        self.assert_not_paused();
+        let quote = self.internal_assert_store_deposit();
+        if let Err(err) = self.internal_check_store_prefix(&prefix) {
+            env::panic_str(&err)
+        }
         let current_account = env::current_account_id().to_string();
         let subaccount: AccountId = format!("{prefix}.{current_account}").parse().unwrap();
-        let init_args = serde_json::to_vec(&FtData {
+        let ft_data = FtData {
             owner_id: env::signer_account_id(),
             marketplace_contract_id: env::current_account_id(),
             name,
@@ -366,14 +2054,14 @@ impl PiparContractFactory {
             city,
             state,
             country,
-        })
-        .unwrap();
+        };
+        let init_args = serde_json::to_vec(&ft_data).unwrap();
 
         Promise::new(subaccount.clone())
             .create_account()
             .add_full_access_key(env::signer_account_pk())
-            .transfer(STORE_BALANCE)
-            .deploy_contract(include_bytes!("../wasm/store.wasm").to_vec())
+            .transfer(quote.store_balance.into())
+            .deploy_contract(self.internal_store_code(&self.default_store_code_hash))
             .function_call("new_default_meta".to_owned(), init_args, NO_DEPOSIT, PGAS)
             .then(
                 Self::ext(env::current_account_id())
@@ -382,6 +2070,8 @@ impl PiparContractFactory {
                         env::signer_account_id(),
                         prefix.clone(),
                         U128::from(env::attached_deposit()),
+                        quote,
+                        self.internal_store_record(&subaccount, &prefix, &ft_data, false),
                     ),
             )
     }
@@ -399,47 +2089,54 @@ impl PiparContractFactory {
         nonce: String,
         affiliate: Option<AccountId>,
         keypom_id: AccountId,
+        referrer: Option<AccountId>,
+        quantity: Option<u32>,
     ) -> Promise {
-        let check_existing = self
-            .transactions
-            .iter()
-            .position(|t| {
-                t.product_id == product_id
-                    && t.store_contract_id == store_contract_id
-                    && t.buyer_id == keypom_id
-            })
-            .unwrap_or_else(|| 11111111);
+        self.assert_not_paused();
+        let quantity = quantity.unwrap_or(1);
+        self.internal_assert_order_quantity(&store_contract_id, quantity);
+        self.internal_assert_no_open_purchase(&keypom_id, &store_contract_id, product_id);
+        // Keypom claims come from accounts without a storage balance, so the order's storage
+        // is paid out of the attached deposit and only the rest goes towards the purchase.
+        let storage_deposit = (PURCHASE_STORAGE_BYTES + (hashed_billing_address.len() + nonce.len()) as u64) as u128
+            * env::storage_byte_cost();
+        assert!(
+            env::attached_deposit() > storage_deposit,
+            "The attached deposit must cover {} yoctoNEAR of order storage on top of the price",
+            storage_deposit
+        );
+        let amount = env::attached_deposit() - storage_deposit;
 
-        match self.transactions.get(check_existing as u64) {
-            Some(t) => panic!("Cannot escrow buy twice on the same product with the same seller, you must complete one first: {:?}", t),
-            None => {
-                let args = serde_json::to_vec(&Buy {
-                    id: product_id.clone(),
-                    receiver_id: keypom_id.clone(),
-                    attached_deposit: env::attached_deposit().into(),
-                    color: color,
-                    affiliate: affiliate,
-                })
-                    .unwrap();
-                Promise::new(store_contract_id.clone())
-                    .function_call("nft_mint".to_owned(), args, ONE_YOCTO, PGAS)
-                    .then(
-                        Self::ext(env::current_account_id())
-                            .buy_callback(
-                                keypom_id.clone(),
-                                U128::from(env::attached_deposit()),
-                                product_id.clone(),
-                                store_contract_id,
-                                timeout,
-                                is_discount,
-                                is_reward,
-                                true,
-                                hashed_billing_address,
-                                nonce,
-                            )
+        let args = serde_json::to_vec(&Buy {
+            id: product_id.clone(),
+            receiver_id: keypom_id.clone(),
+            attached_deposit: amount.into(),
+            color: color,
+            affiliate: affiliate,
+            ft_token_id: None,
+            quantity,
+        })
+            .unwrap();
+        Promise::new(store_contract_id.clone())
+            .function_call("nft_mint".to_owned(), args, ONE_YOCTO, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .buy_callback(
+                        keypom_id.clone(),
+                        U128::from(amount),
+                        product_id.clone(),
+                        store_contract_id,
+                        timeout,
+                        is_discount,
+                        is_reward,
+                        true,
+                        hashed_billing_address,
+                        nonce,
+                        referrer,
+                        quantity,
+                        U128::from(storage_deposit),
                     )
-            }
-        }
+            )
     }
 
     #[payable]
@@ -453,48 +2150,49 @@ impl PiparContractFactory {
         is_reward: bool,
         hashed_billing_address: String,
         nonce: String,
-        affiliate: Option<AccountId>
+        affiliate: Option<AccountId>,
+        referrer: Option<AccountId>,
+        quantity: Option<u32>,
     ) -> Promise {
-        let check_existing = self
-            .transactions
-            .iter()
-            .position(|t| {
-                t.product_id == product_id
-                    && t.store_contract_id == store_contract_id
-                    && t.buyer_id == env::predecessor_account_id()
-            })
-            .unwrap_or_else(|| 11111111);
+        self.assert_not_paused();
+        let quantity = quantity.unwrap_or(1);
+        self.internal_assert_order_quantity(&store_contract_id, quantity);
+        self.internal_assert_no_open_purchase(&env::predecessor_account_id(), &store_contract_id, product_id);
+        self.internal_assert_storage_available(
+            &env::predecessor_account_id(),
+            PURCHASE_STORAGE_BYTES + (hashed_billing_address.len() + nonce.len()) as u64,
+        );
 
-        match self.transactions.get(check_existing as u64) {
-            Some(t) => panic!("Cannot escrow buy twice on the same product with the same seller, you must complete one first: {:?}", t),
-            None => {
-                let args = serde_json::to_vec(&Buy {
-                    id: product_id.clone(),
-                    receiver_id: env::predecessor_account_id(),
-                    attached_deposit: env::attached_deposit().into(),
-                    color: color,
-                    affiliate: affiliate,
-                })
-                    .unwrap();
-                Promise::new(store_contract_id.clone())
-                    .function_call("nft_mint".to_owned(), args, ONE_YOCTO, PGAS)
-                    .then(
-                        Self::ext(env::current_account_id())
-                            .buy_callback(
-                                env::predecessor_account_id(),
-                                U128::from(env::attached_deposit()),
-                                product_id.clone(),
-                                store_contract_id,
-                                timeout,
-                                is_discount,
-                                is_reward,
-                                false,
-                                hashed_billing_address,
-                                nonce,
-                            )
+        let args = serde_json::to_vec(&Buy {
+            id: product_id.clone(),
+            receiver_id: env::predecessor_account_id(),
+            attached_deposit: env::attached_deposit().into(),
+            color: color,
+            affiliate: affiliate,
+            ft_token_id: None,
+            quantity,
+        })
+            .unwrap();
+        Promise::new(store_contract_id.clone())
+            .function_call("nft_mint".to_owned(), args, ONE_YOCTO, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .buy_callback(
+                        env::predecessor_account_id(),
+                        U128::from(env::attached_deposit()),
+                        product_id.clone(),
+                        store_contract_id,
+                        timeout,
+                        is_discount,
+                        is_reward,
+                        false,
+                        hashed_billing_address,
+                        nonce,
+                        referrer,
+                        quantity,
+                        U128(0),
                     )
-            }
-        }
+            )
     }
 
     #[private]
@@ -510,6 +2208,9 @@ impl PiparContractFactory {
         is_keypom: bool,
         hashed_billing_address: String,
         nonce: String,
+        referrer: Option<AccountId>,
+        quantity: u32,
+        storage_deposit: U128,
     ) -> Option<MarketplaceData> {
         let attached_deposit: u128 = attached_deposit.into();
         assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
@@ -519,26 +2220,28 @@ impl PiparContractFactory {
         },
             PromiseResult::Successful(value) => {
                 if let Ok(result) = serde_json::from_slice::<MarketplaceData>(&value) {
-                    self.transactions.push(&Transaction {
-                        transaction_id: U128::from(env::block_timestamp() as u128),
-                        product_id: product_id,
-                        store_contract_id,
+                    let purchase = PendingPurchase {
                         buyer_id: buyer_account_id,
-                        buyer_value_locked: attached_deposit.into(),
-                        price: result.price.clone(),
-                        token_id: result.token_id.clone(),
+                        amount: U128::from(attached_deposit),
+                        product_id,
+                        store_contract_id,
                         timeout,
-                        affiliate: result.affiliate.clone(),
-                        affiliate_id: result.affiliate_id.clone(),
-                        affiliate_percentage: result.affiliate_percentage.clone(),
                         is_discount,
                         is_reward,
                         is_keypom,
-                        status: TransactionStatus::Approved,
                         hashed_billing_address,
                         nonce,
-                        ipfs: String::from(""),
-                    });
+                        ft_token_id: None,
+                        referrer_id: referrer,
+                        cart_id: None,
+                        quantity,
+                        storage_deposit,
+                    };
+                    if let Err(reason) = check_mint(&result, attached_deposit, quantity) {
+                        self.internal_reject_mint(result, purchase, reason);
+                        return None;
+                    }
+                    self.internal_record_purchase(&result, purchase);
                     env::log_str("Successfully purchased product");
                     Some(result.clone())
                 } else {
@@ -547,103 +2250,364 @@ impl PiparContractFactory {
                 }
             },
             PromiseResult::Failed => {
-                Promise::new(buyer_account_id).transfer(attached_deposit);
+                let refund = attached_deposit + u128::from(storage_deposit);
+                Promise::new(buyer_account_id.clone()).transfer(refund);
+                emit_payout_event(None, &buyer_account_id, refund, "purchase_refund");
                 None
             },
         }
     }
 
-    pub fn complete_purchase(
+    #[payable]
+    pub fn buy_cart(
         &mut self,
-        transaction_id: U128,
-        store_contract_id: AccountId,
+        lines: Vec<CartLine>,
+        timeout: U128,
+        hashed_billing_address: String,
+        nonce: String,
+        referrer: Option<AccountId>,
     ) -> Promise {
-        let check_existing = self
-            .transactions
-            .iter()
-            .position(|t| {
-                t.transaction_id == transaction_id
-                    && t.store_contract_id == store_contract_id
-                    && t.buyer_id == env::predecessor_account_id()
-                    && t.status == TransactionStatus::Shipped
+        self.assert_not_paused();
+        let buyer_id = env::predecessor_account_id();
+        assert!(!lines.is_empty(), "Cart is empty");
+        assert!(lines.len() <= MAX_CART_LINES, "A cart can hold at most {} lines", MAX_CART_LINES);
+        let mut total: u128 = 0;
+        for (i, line) in lines.iter().enumerate() {
+            self.internal_assert_order_quantity(&line.store_contract_id, line.quantity);
+            assert!(
+                !lines[..i].iter().any(|other| {
+                    other.store_contract_id == line.store_contract_id && other.product_id == line.product_id
+                }),
+                "Product {} from {} appears twice in the cart",
+                u64::from(line.product_id),
+                line.store_contract_id
+            );
+            self.internal_assert_no_open_purchase(&buyer_id, &line.store_contract_id, line.product_id);
+            total += u128::from(line.amount);
+        }
+        assert_eq!(total, env::attached_deposit(), "Attached deposit must equal the sum of the cart lines");
+        let units: u32 = lines.iter().map(|line| line.quantity).sum();
+        assert!(units <= MAX_ORDER_QUANTITY, "A cart can hold at most {} units", MAX_ORDER_QUANTITY);
+        self.internal_assert_storage_available(
+            &buyer_id,
+            (PURCHASE_STORAGE_BYTES + (hashed_billing_address.len() + nonce.len()) as u64) * lines.len() as u64,
+        );
+
+        let cart_id = self.next_cart_id;
+        self.next_cart_id += 1;
+        let mut mints: Option<Promise> = None;
+        for line in lines.iter() {
+            let args = serde_json::to_vec(&Buy {
+                id: line.product_id,
+                receiver_id: buyer_id.clone(),
+                attached_deposit: line.amount,
+                color: line.color.clone(),
+                affiliate: line.affiliate.clone(),
+                ft_token_id: None,
+                quantity: line.quantity,
             })
-            .unwrap_or_else(|| 11111111);
+                .unwrap();
+            let mint = Promise::new(line.store_contract_id.clone())
+                .function_call("nft_mint".to_owned(), args, ONE_YOCTO, PGAS);
+            mints = Some(match mints {
+                Some(mints) => mints.and(mint),
+                None => mint,
+            });
+        }
+        mints.unwrap().then(
+            Self::ext(env::current_account_id())
+                .buy_cart_callback(
+                    buyer_id,
+                    U64::from(cart_id),
+                    lines,
+                    timeout,
+                    hashed_billing_address,
+                    nonce,
+                    referrer,
+                )
+        )
+    }
 
-        match self.transactions.get(check_existing as u64) {
-            Some(t) => {
-                if t.is_reward == true {
-                    let args = serde_json::to_vec(&TokenData {
-                        id: t.product_id,
-                        receiver_id: t.buyer_id,
-                    })
-                    .unwrap();
-                    Promise::new(store_contract_id.clone())
-                        .function_call("reward_with_token".to_owned(), args, NO_DEPOSIT, PGAS)
-                        .then(
-                            Self::ext(env::current_account_id())
-                                .complete_purchase_callback(check_existing as u64),
-                        )
+    #[private]
+    pub fn buy_cart_callback(
+        &mut self,
+        buyer_account_id: AccountId,
+        cart_id: U64,
+        lines: Vec<CartLine>,
+        timeout: U128,
+        hashed_billing_address: String,
+        nonce: String,
+        referrer: Option<AccountId>,
+    ) -> Vec<U128> {
+        assert_eq!(env::promise_results_count(), lines.len() as u64, "ERR_TOO_MANY_RESULTS");
+        let mut transaction_ids: Vec<U128> = vec![];
+        let mut refund: u128 = 0;
+        for (i, line) in lines.into_iter().enumerate() {
+            let result = match env::promise_result(i as u64) {
+                PromiseResult::NotReady => unreachable!(),
+                PromiseResult::Successful(value) => serde_json::from_slice::<MarketplaceData>(&value).ok(),
+                PromiseResult::Failed => None,
+            };
+            match result {
+                Some(result) => {
+                    let purchase = PendingPurchase {
+                        buyer_id: buyer_account_id.clone(),
+                        amount: line.amount,
+                        product_id: line.product_id,
+                        store_contract_id: line.store_contract_id,
+                        timeout,
+                        is_discount: line.is_discount,
+                        is_reward: line.is_reward,
+                        is_keypom: false,
+                        hashed_billing_address: hashed_billing_address.clone(),
+                        nonce: nonce.clone(),
+                        ft_token_id: None,
+                        referrer_id: referrer.clone(),
+                        cart_id: Some(cart_id),
+                        quantity: line.quantity,
+                        storage_deposit: U128(0),
+                    };
+                    // Rejected lines are refunded on their own once their tokens are burned.
+                    match check_mint(&result, line.amount.into(), line.quantity) {
+                        Ok(()) => {
+                            let transaction = self.internal_record_purchase(&result, purchase);
+                            transaction_ids.push(transaction.transaction_id);
+                        }
+                        Err(reason) => {
+                            self.internal_reject_mint(result, purchase, reason);
+                        }
+                    }
+                }
+                None => refund += u128::from(line.amount),
+            }
+        }
+
+        if !transaction_ids.is_empty() {
+            self.cart_transactions.insert(&u64::from(cart_id), &transaction_ids);
+        }
+        if refund > 0 {
+            Promise::new(buyer_account_id.clone()).transfer(refund);
+            emit_payout_event(None, &buyer_account_id, refund, "purchase_refund");
+        }
+        emit_event("cart_checked_out", json!({
+            "cart_id": cart_id,
+            "buyer_id": buyer_account_id,
+            "transaction_ids": transaction_ids,
+            "refunded": U128::from(refund),
+        }));
+        transaction_ids
+    }
+
+    pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        self.assert_not_paused();
+        let ft_token_id = env::predecessor_account_id();
+        assert!(
+            self.accepted_tokens.contains(&ft_token_id),
+            "Token {} is not accepted for purchases",
+            ft_token_id
+        );
+        let purchase: FtPurchaseMsg = serde_json::from_str(&msg).expect("Invalid purchase message");
+        self.internal_assert_order_quantity(&purchase.store_contract_id, purchase.quantity.unwrap_or(1));
+        self.internal_assert_no_open_purchase(&sender_id, &purchase.store_contract_id, purchase.product_id);
+        self.internal_assert_storage_available(
+            &sender_id,
+            PURCHASE_STORAGE_BYTES + (purchase.hashed_billing_address.len() + purchase.nonce.len()) as u64,
+        );
+
+        let args = serde_json::to_vec(&Buy {
+            id: purchase.product_id,
+            receiver_id: sender_id.clone(),
+            attached_deposit: amount,
+            color: purchase.color.clone(),
+            affiliate: purchase.affiliate.clone(),
+            ft_token_id: Some(ft_token_id.clone()),
+            quantity: purchase.quantity.unwrap_or(1),
+        })
+            .unwrap();
+        Promise::new(purchase.store_contract_id.clone())
+            .function_call("nft_mint".to_owned(), args, ONE_YOCTO, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .ft_buy_callback(sender_id, amount, ft_token_id, purchase)
+            )
+            .into()
+    }
+
+    #[private]
+    pub fn ft_buy_callback(
+        &mut self,
+        buyer_account_id: AccountId,
+        amount: U128,
+        ft_token_id: AccountId,
+        purchase: FtPurchaseMsg,
+    ) -> PromiseOrValue<U128> {
+        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(value) => {
+                if let Ok(result) = serde_json::from_slice::<MarketplaceData>(&value) {
+                    let quantity = purchase.quantity.unwrap_or(1);
+                    let pending = PendingPurchase {
+                        buyer_id: buyer_account_id,
+                        amount,
+                        product_id: purchase.product_id,
+                        store_contract_id: purchase.store_contract_id,
+                        timeout: purchase.timeout,
+                        is_discount: purchase.is_discount,
+                        is_reward: purchase.is_reward,
+                        is_keypom: false,
+                        hashed_billing_address: purchase.hashed_billing_address,
+                        nonce: purchase.nonce,
+                        ft_token_id: Some(ft_token_id),
+                        referrer_id: purchase.referrer,
+                        cart_id: None,
+                        quantity,
+                        storage_deposit: U128(0),
+                    };
+                    if let Err(reason) = check_mint(&result, amount.into(), quantity) {
+                        return self.internal_reject_mint(result, pending, reason).into();
+                    }
+                    self.internal_record_purchase(&result, pending);
+                    env::log_str("Successfully purchased product");
+                    PromiseOrValue::Value(U128(0))
                 } else {
-                    Promise::new(env::current_account_id())
-                        .then(
-                        Self::ext(env::current_account_id())
-                            .complete_purchase_callback(check_existing.clone() as u64),
-                    )
+                    env::log_str("The batch call failed and all calls got reverted");
+                    PromiseOrValue::Value(amount)
                 }
+            },
+            // Returning the full amount makes the token contract refund the buyer.
+            PromiseResult::Failed => PromiseOrValue::Value(amount),
+        }
+    }
+
+    // Returns the amount refunded to the buyer, which is also what the token contract
+    // hands back for token purchases.
+    #[private]
+    pub fn reject_mint_callback(&mut self, result: MarketplaceData, purchase: PendingPurchase) -> U128 {
+        if all_promises_succeeded() {
+            let amount: u128 = purchase.amount.into();
+            if purchase.ft_token_id.is_none() {
+                let refund = amount + u128::from(purchase.storage_deposit);
+                Promise::new(purchase.buyer_id.clone()).transfer(refund);
+                emit_payout_event(None, &purchase.buyer_id, refund, "purchase_refund");
+                return purchase.amount;
+            }
+            emit_payout_event(None, &purchase.buyer_id, amount, "purchase_refund");
+            return purchase.amount;
+        }
+
+        // Shares the seller's funds cannot cover are dropped so the arbiter can still settle.
+        let mut result = result;
+        if check_payout_shares(&result).is_err() {
+            result.affiliate = false;
+            result.royalty.clear();
+        }
+        let cart_id = purchase.cart_id;
+        let mut t = self.internal_record_purchase(&result, purchase);
+        t.status = TransactionStatus::Disputed;
+        self.internal_update_transaction(&mut t);
+        if let Some(cart_id) = cart_id {
+            let mut transaction_ids = self.cart_transactions.get(&u64::from(cart_id)).unwrap_or_default();
+            transaction_ids.push(t.transaction_id);
+            self.cart_transactions.insert(&u64::from(cart_id), &transaction_ids);
+        }
+        emit_order_event("order_disputed", &t);
+        env::log_str("The store could not burn the rejected tokens, the payment is held for an arbiter");
+        U128(0)
+    }
+
+    pub fn complete_purchase(
+        &mut self,
+        transaction_id: U128,
+        store_contract_id: AccountId,
+        quantity: Option<u32>,
+    ) -> Promise {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&env::predecessor_account_id()),
+            TransactionStatus::Shipped,
+        ) {
+            Some(t) => {
+                let pending = t.shipped_quantity - t.delivered_quantity;
+                let units = quantity.unwrap_or(pending);
+                assert!(units > 0 && units <= pending, "Only shipped units can be confirmed, {} are pending", pending);
+                self.internal_complete_purchase(t, units)
             }
             None => panic!("Cannot complete transaction at this time, please try again later"),
         }
     }
 
+    pub fn release_escrow(&mut self, transaction_id: U128, store_contract_id: AccountId) -> Promise {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            None,
+            TransactionStatus::Shipped,
+        ) {
+            Some(t) => {
+                let deadline: u64 = t.confirmation_deadline.expect("Transaction has no confirmation deadline").into();
+                assert!(
+                    env::block_timestamp() >= deadline,
+                    "Delivery confirmation window is still open, please try again later"
+                );
+                let units = t.shipped_quantity - t.delivered_quantity;
+                assert!(units > 0, "No shipped units are awaiting confirmation");
+                emit_order_event("order_auto_released", &t);
+                self.internal_complete_purchase(t, units)
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
     #[private]
-    pub fn complete_purchase_callback(&mut self, check_existing: u64) {
+    pub fn complete_purchase_callback(&mut self, transaction_id: U128, units: u32) {
         if is_promise_success() {
-            match self.transactions.get(check_existing.clone() as u64) {
-                Some(t) => {
-                    self.transactions.replace(
-                        check_existing as u64,
-                        &Transaction {
-                            transaction_id: t.transaction_id,
-                            product_id: t.product_id,
-                            store_contract_id: t.store_contract_id.clone(),
-                            buyer_id: t.buyer_id.clone(),
-                            buyer_value_locked: t.buyer_value_locked,
-                            price: t.price,
-                            token_id: t.token_id.clone(),
-                            timeout: t.timeout,
-                            affiliate: t.affiliate.clone(),
-                            affiliate_id: t.affiliate_id.clone(),
-                            affiliate_percentage: t.affiliate_percentage.clone(),
-                            is_discount: t.is_discount,
-                            is_reward: t.is_reward,
-                            is_keypom: t.is_keypom,
-                            status: TransactionStatus::Delivered,
-                            hashed_billing_address: t.hashed_billing_address,
-                            nonce: t.nonce,
-                            ipfs: t.ipfs
-                        },
-                    );
-                    let payout: u128 = t.buyer_value_locked.into();
-                    let percent = payout as f64 * 0.98;
-                    let seller_funds = percent as u128;
-                    if let Some(affix) = t.affiliate_id.clone() {
-                        if t.affiliate == true {
+            match self.internal_get_transaction(transaction_id.into()) {
+                Some(mut t) => {
+                    if t.status != TransactionStatus::Shipped || units > t.shipped_quantity - t.delivered_quantity {
+                        env::log_str("Transaction is no longer awaiting delivery");
+                        return;
+                    }
+                    let value = t.value_of_units(t.delivered_quantity + units) - t.value_of_units(t.delivered_quantity);
+                    t.delivered_quantity += units;
+                    // Stores with a return policy are paid once the return window has passed.
+                    let held = t.return_policy.is_some();
+                    if !held {
+                        t.released_value = U128::from(u128::from(t.released_value) + value);
+                    }
+                    let delivered = t.delivered_quantity == t.quantity;
+                    if delivered {
+                        t.status = TransactionStatus::Delivered;
+                        if let Some(policy) = t.return_policy.as_ref() {
+                            t.return_deadline = Some(U64::from(self.calculate_timeout(
+                                U128::from(policy.window_days as u128),
+                                U128::from(env::block_timestamp() as u128),
+                            ) as u64));
+                        }
+                    }
+                    self.internal_update_transaction(&mut t);
+                    if delivered && t.affiliate == true && t.affiliate_id.is_some() {
+                        for token_id in t.token_ids.iter() {
                             let args = serde_json::to_vec(&Token {
-                                token_id: t.token_id.clone(),
+                                token_id: token_id.clone(),
                             })
                                 .unwrap();
-                            let percentage = t.affiliate_percentage.unwrap_or(0);
-                            let affiliate_payout = seller_funds as f64 / 100.0 * percentage as f64;
-                            Promise::new(t.store_contract_id.clone()).transfer(seller_funds - affiliate_payout as Balance)
-                                .function_call("unlock_token".to_owned(), args, NO_DEPOSIT, PGAS)
-                                .then(
-                                    Promise::new(affix).transfer(affiliate_payout as Balance)
-                                );
-                        } else {
-                            Promise::new(t.store_contract_id.clone()).transfer(seller_funds);
+                            Promise::new(t.store_contract_id.clone())
+                                .function_call("unlock_token".to_owned(), args, NO_DEPOSIT, UNLOCK_TOKEN_GAS);
                         }
                     }
-                    env::log_str("Successful transaction completion")
+                    if !held {
+                        let payouts = self.internal_release_payouts(&t, value);
+                        self.internal_execute_payouts(&t, &payouts);
+                    }
+                    if delivered {
+                        emit_order_event("order_delivered", &t);
+                        env::log_str("Successful transaction completion")
+                    } else {
+                        emit_order_event("order_units_delivered", &t);
+                        env::log_str("Delivered units have been released to the seller")
+                    }
                 }
                 None => panic!("Transaction not found"),
             }
@@ -653,69 +2617,118 @@ impl PiparContractFactory {
     }
 
     pub fn dispute_purchase(&mut self, transaction_id: U128, store_contract_id: AccountId) {
-        let check_existing = self
-            .transactions
-            .iter()
-            .position(|t| {
-                t.transaction_id == transaction_id
-                    && t.store_contract_id == store_contract_id
-                    && t.buyer_id == env::predecessor_account_id()
-                    && t.status == TransactionStatus::Shipped
+        let buyer_id = env::predecessor_account_id();
+        match self
+            .internal_find_transaction(transaction_id, &store_contract_id, Some(&buyer_id), TransactionStatus::Shipped)
+            .or_else(|| {
+                self.internal_find_transaction(transaction_id, &store_contract_id, Some(&buyer_id), TransactionStatus::ReturnRequested)
             })
-            .unwrap_or_else(|| 11111111);
-
-        match self.transactions.get(check_existing.clone() as u64) {
-            Some(t) => {
-                self.transactions.replace(
-                    check_existing as u64,
-                    &Transaction {
-                        transaction_id: t.transaction_id,
-                        product_id: t.product_id,
-                        store_contract_id: t.store_contract_id,
-                        buyer_id: t.buyer_id,
-                        buyer_value_locked: t.buyer_value_locked,
-                        price: t.price,
-                        token_id: t.token_id,
-                        timeout: t.timeout,
-                        affiliate: t.affiliate,
-                        affiliate_id: t.affiliate_id,
-                        affiliate_percentage: t.affiliate_percentage,
-                        is_discount: t.is_discount,
-                        is_reward: t.is_reward,
-                        is_keypom: t.is_keypom,
-                        status: TransactionStatus::Disputed,
-                        hashed_billing_address: t.hashed_billing_address,
-                        nonce: t.nonce,
-                        ipfs: t.ipfs
-                    },
-                );
+        {
+            Some(mut t) => {
+                if let (TransactionStatus::Shipped, Some(deadline)) = (&t.status, t.confirmation_deadline) {
+                    assert!(
+                        env::block_timestamp() < u64::from(deadline),
+                        "Delivery confirmation window has closed, the transaction can no longer be disputed"
+                    );
+                }
+                t.status = TransactionStatus::Disputed;
+                self.internal_update_transaction(&mut t);
+                emit_order_event("order_disputed", &t);
                 env::log_str("Transaction has been marked disputed")
             }
             None => panic!("Transaction not found"),
         }
     }
 
-    pub fn mark_shipped(&mut self, transaction_id: U128, buyer_id: AccountId, store_contract_id: AccountId, ipfs: String) -> Promise {
-        let check_existing = self
-            .transactions
-            .iter()
-            .position(|t| {
-                t.transaction_id == transaction_id
-                    && t.store_contract_id == store_contract_id
-                    && t.buyer_id == buyer_id
-                    && t.status == TransactionStatus::Approved
-            })
-            .unwrap_or_else(|| 11111111);
+    pub fn resolve_dispute(
+        &mut self,
+        transaction_id: U128,
+        store_contract_id: AccountId,
+        buyer_share_bps: u16,
+        reason: String,
+    ) {
+        self.assert_only_arbiter();
+        assert!(
+            buyer_share_bps as u128 <= BASIS_POINTS,
+            "Buyer share cannot exceed {} basis points",
+            BASIS_POINTS
+        );
 
-        match self.transactions.get(check_existing as u64) {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            None,
+            TransactionStatus::Disputed,
+        ) {
+            Some(mut t) => {
+                // Units already confirmed were paid out, so only the remaining escrow is split.
+                let locked = t.escrow_balance();
+                let buyer_payout = bps_of(locked, buyer_share_bps as u128);
+                let seller_payout = locked - buyer_payout;
+
+                t.status = TransactionStatus::Resolved;
+                t.dispute_resolution = Some(DisputeResolution {
+                    arbiter_id: env::predecessor_account_id(),
+                    buyer_share_bps,
+                    buyer_payout: U128::from(buyer_payout),
+                    seller_payout: U128::from(seller_payout),
+                    reason,
+                    resolved_at: U64::from(env::block_timestamp()),
+                });
+                t.released_value = t.buyer_value_locked;
+                self.internal_update_transaction(&mut t);
+
+                let mut payouts = vec![Payout {
+                    receiver_id: t.buyer_id.clone(),
+                    amount: U128::from(buyer_payout),
+                    kind: PayoutKind::Buyer,
+                }];
+                payouts.extend(self.internal_release_payouts(&t, seller_payout));
+                self.internal_execute_payouts(&t, &payouts);
+                emit_order_event("order_dispute_resolved", &t);
+                env::log_str("Transaction dispute has been resolved")
+            }
+            None => panic!("Disputed transaction not found"),
+        }
+    }
+
+    pub fn mark_shipped(
+        &mut self,
+        transaction_id: U128,
+        buyer_id: AccountId,
+        store_contract_id: AccountId,
+        ipfs: String,
+        quantity: Option<u32>,
+        carrier: Option<String>,
+        tracking_code_hash: Option<String>,
+    ) -> Promise {
+        match self
+            .internal_find_transaction(transaction_id, &store_contract_id, Some(&buyer_id), TransactionStatus::Approved)
+            .or_else(|| self.internal_find_transaction(transaction_id, &store_contract_id, Some(&buyer_id), TransactionStatus::Shipped))
+        {
             Some(t) => {
+                let remaining = t.quantity - t.shipped_quantity;
+                let units = quantity.unwrap_or(remaining);
+                assert!(units > 0 && units <= remaining, "Only unshipped units can be shipped, {} remain", remaining);
+                let update = TrackingUpdate {
+                    carrier,
+                    tracking_code_hash,
+                    ipfs,
+                    timestamp: U64::from(env::block_timestamp()),
+                    author_id: env::signer_account_id(),
+                };
+                // The latest proof is also kept on the transaction itself.
+                self.internal_assert_store_storage_available(
+                    &t.store_contract_id,
+                    Self::tracking_update_bytes(&update) + update.ipfs.len() as u64,
+                );
                 let args = serde_json::to_vec(&EmptyData {})
                     .unwrap();
                 Promise::new(t.store_contract_id.clone())
                     .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
                     .then(
                         Self::ext(env::current_account_id())
-                            .mark_shipped_callback(check_existing.clone() as u64, ipfs),
+                            .mark_shipped_callback(t.transaction_id, update, units),
                     )
             }
             None => panic!("Transaction not found"),
@@ -723,77 +2736,2287 @@ impl PiparContractFactory {
     }
 
     #[private]
-    pub fn mark_shipped_callback(&mut self, check_existing: u64, ipfs: String) {
-        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
-        match env::promise_result(0) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(val) => {
-                let result: bool = serde_json::from_slice::<bool>(&*val).unwrap();
-                if result {
-                    match self.transactions.get(check_existing.clone() as u64) {
-                        Some(t) => {
-                            self.transactions.replace(
-                                check_existing as u64,
-                                &Transaction {
-                                    transaction_id: t.transaction_id,
-                                    product_id: t.product_id,
-                                    store_contract_id: t.store_contract_id,
-                                    buyer_id: t.buyer_id,
-                                    buyer_value_locked: t.buyer_value_locked,
-                                    price: t.price,
-                                    token_id: t.token_id,
-                                    timeout: t.timeout,
-                                    affiliate: t.affiliate,
-                                    affiliate_id: t.affiliate_id,
-                                    affiliate_percentage: t.affiliate_percentage,
-                                    is_discount: t.is_discount,
-                                    is_reward: t.is_reward,
-                                    is_keypom: t.is_keypom,
-                                    status: TransactionStatus::Shipped,
-                                    hashed_billing_address: t.hashed_billing_address,
-                                    nonce: t.nonce,
-                                    ipfs
-                                },
-                            );
-                            env::log_str("Transaction has been marked shipped")
-                        }
-                        None => panic!("Transaction not found"),
-                    }
-                } else {
-                    env::panic_str("Product Marked shipped failed, please try again")
+    pub fn mark_shipped_callback(&mut self, transaction_id: U128, update: TrackingUpdate, units: u32) {
+        Self::internal_assert_store_owner_result("Product Marked shipped failed, please try again");
+        match self.internal_get_transaction(transaction_id.into()) {
+            Some(mut t) => {
+                assert!(
+                    matches!(t.status, TransactionStatus::Approved | TransactionStatus::Shipped)
+                        && units <= t.quantity - t.shipped_quantity,
+                    "Transaction can no longer be shipped"
+                );
+                t.status = TransactionStatus::Shipped;
+                t.ipfs = update.ipfs.clone();
+                t.shipped_quantity += units;
+                if t.shipped_at.is_none() {
+                    t.shipped_at = Some(U64::from(env::block_timestamp()));
                 }
-            },
-            PromiseResult::Failed => env::panic_str("Product Marked shipped failed, please try again"),
+                t.confirmation_deadline = Some(U64::from(self.calculate_timeout(
+                    U128::from(self.confirmation_window_days as u128),
+                    U128::from(env::block_timestamp() as u128),
+                ) as u64));
+                let initial_storage = env::storage_usage();
+                self.internal_update_transaction(&mut t);
+                self.internal_append_tracking(&t, TrackingUpdate {
+                    timestamp: U64::from(env::block_timestamp()),
+                    ..update
+                });
+                let bytes = env::storage_usage().saturating_sub(initial_storage);
+                t.store_storage_bytes += self.internal_charge_store_storage(&t.store_contract_id, bytes);
+                self.internal_update_transaction(&mut t);
+                emit_order_event("order_shipped", &t);
+                env::log_str("Transaction has been marked shipped")
+            }
+            None => panic!("Transaction not found"),
         }
     }
 
-    pub fn get_refund(&mut self, transaction_id: U128, store_contract_id: AccountId) {
-        let check_existing = self
-            .transactions
-            .iter()
-            .position(|t| {
-                t.transaction_id == transaction_id
-                    && t.store_contract_id == store_contract_id
-                    && t.buyer_id == env::predecessor_account_id()
-                    && t.status == TransactionStatus::Approved
-            })
-            .unwrap_or_else(|| 11111111);
+    pub fn add_tracking_update(
+        &mut self,
+        transaction_id: U128,
+        buyer_id: AccountId,
+        store_contract_id: AccountId,
+        carrier: Option<String>,
+        tracking_code_hash: Option<String>,
+        ipfs: String,
+    ) -> Promise {
+        let t = self.internal_get_transaction(transaction_id.into()).expect("Transaction not found");
+        assert!(
+            t.store_contract_id == store_contract_id && t.buyer_id == buyer_id,
+            "Transaction not found"
+        );
+        assert!(t.status.is_open(), "Tracking can only be updated on open orders");
+        let update = TrackingUpdate {
+            carrier,
+            tracking_code_hash,
+            ipfs,
+            timestamp: U64::from(env::block_timestamp()),
+            author_id: env::signer_account_id(),
+        };
+        self.internal_assert_store_storage_available(&t.store_contract_id, Self::tracking_update_bytes(&update));
+        let args = serde_json::to_vec(&EmptyData {})
+            .unwrap();
+        Promise::new(t.store_contract_id.clone())
+            .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .add_tracking_update_callback(t.transaction_id, update),
+            )
+    }
 
-        match self.transactions.get(check_existing as u64) {
-            Some(t) => {
-                let timeout = self.calculate_timeout(t.timeout, t.transaction_id);
-                let current_timestamp = env::block_timestamp() as u128;
+    #[private]
+    pub fn add_tracking_update_callback(&mut self, transaction_id: U128, update: TrackingUpdate) {
+        Self::internal_assert_store_owner_result("Only the store owner can post tracking updates");
 
-                if current_timestamp >= timeout {
-                    let attached_deposit: u128 = t.buyer_value_locked.into();
-                    env::log_str("Transaction time has elapsed, returning funds to the buyer");
-                    Promise::new(env::predecessor_account_id()).transfer(attached_deposit);
-                } else {
-                    panic!("Transaction time is yet to elapsed, please try again later")
-                }
+        let mut t = self.internal_get_transaction(transaction_id.into()).expect("Transaction not found");
+        assert!(t.status.is_open(), "Tracking can only be updated on open orders");
+        let initial_storage = env::storage_usage();
+        self.internal_append_tracking(&t, TrackingUpdate {
+            timestamp: U64::from(env::block_timestamp()),
+            ..update
+        });
+        let bytes = env::storage_usage().saturating_sub(initial_storage);
+        t.store_storage_bytes += self.internal_charge_store_storage(&t.store_contract_id, bytes);
+        self.internal_update_transaction(&mut t);
+        emit_order_event("order_tracking_updated", &t);
+    }
+
+    pub fn get_tracking(&self, transaction_id: U128) -> Vec<TrackingUpdate> {
+        self.tracking
+            .get(&transaction_id.into())
+            .map_or(vec![], |updates| updates.to_vec())
+    }
+
+    pub fn submit_review(&mut self, transaction_id: U128, store_contract_id: AccountId, rating: u8, ipfs: String) {
+        let buyer_id = env::predecessor_account_id();
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&buyer_id),
+            TransactionStatus::Delivered,
+        ) {
+            Some(mut t) => {
+                assert!((1..=5).contains(&rating), "Rating must be between 1 and 5");
+                let id: u128 = t.transaction_id.into();
+                assert!(self.reviews.get(&id).is_none(), "This order has already been reviewed");
+                assert!(
+                    self.internal_closed_store_generation(&t).is_none(),
+                    "Store {} has been closed",
+                    store_contract_id
+                );
+                self.internal_assert_storage_available(&buyer_id, REVIEW_STORAGE_BYTES + ipfs.len() as u64);
+
+                let initial_storage = env::storage_usage();
+                let review = Review {
+                    transaction_id: t.transaction_id,
+                    store_contract_id: t.store_contract_id.clone(),
+                    buyer_id: buyer_id.clone(),
+                    product_id: t.product_id,
+                    rating,
+                    ipfs,
+                    created_at: U64::from(env::block_timestamp()),
+                    reply: None,
+                };
+                self.reviews.insert(&id, &review);
+                let mut review_ids = self.store_reviews.get(&t.store_contract_id).unwrap_or_else(|| {
+                    Vector::new(StorageKey::StoreReviewIds {
+                        account_hash: self.internal_store_index_hash(&t.store_contract_id),
+                    })
+                });
+                review_ids.push(&id);
+                self.store_reviews.insert(&t.store_contract_id, &review_ids);
+
+                let mut reputation = self.store_reputations.get(&t.store_contract_id).unwrap_or_default();
+                reputation.review_count += 1;
+                reputation.rating_total += rating as u64;
+                reputation.rating_counts[rating as usize - 1] += 1;
+                reputation.average_rating = (reputation.rating_total * 100 / reputation.review_count) as u32;
+                self.store_reputations.insert(&t.store_contract_id, &reputation);
+                // The review is pruned along with its order, so its bytes count towards the order.
+                let bytes = env::storage_usage().saturating_sub(initial_storage);
+                self.internal_charge_storage(&buyer_id, bytes);
+                t.buyer_storage_bytes += bytes;
+                self.internal_update_transaction(&mut t);
+
+                emit_event("review_submitted", json!({
+                    "transaction_id": review.transaction_id,
+                    "store_contract_id": review.store_contract_id,
+                    "buyer_id": review.buyer_id,
+                    "rating": rating,
+                    "ipfs": review.ipfs,
+                }));
+            }
+            None => panic!("Only delivered orders can be reviewed by their buyer"),
+        }
+    }
+
+    pub fn reply_to_review(&mut self, transaction_id: U128, ipfs: String) -> Promise {
+        let review = self.reviews.get(&transaction_id.into()).expect("Review not found");
+        assert!(review.reply.is_none(), "This review already has a reply");
+        self.internal_assert_store_storage_available(&review.store_contract_id, REVIEW_STORAGE_BYTES + ipfs.len() as u64);
+        let args = serde_json::to_vec(&EmptyData {})
+            .unwrap();
+        Promise::new(review.store_contract_id)
+            .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .reply_to_review_callback(transaction_id, ipfs, env::signer_account_id()),
+            )
+    }
+
+    #[private]
+    pub fn reply_to_review_callback(&mut self, transaction_id: U128, ipfs: String, author_id: AccountId) {
+        Self::internal_assert_store_owner_result("Only the store owner can reply to reviews");
+
+        let id: u128 = transaction_id.into();
+        let mut review = self.reviews.get(&id).expect("Review not found");
+        assert!(review.reply.is_none(), "This review already has a reply");
+        let initial_storage = env::storage_usage();
+        review.reply = Some(ReviewReply {
+            ipfs,
+            author_id,
+            created_at: U64::from(env::block_timestamp()),
+        });
+        self.reviews.insert(&id, &review);
+        let bytes = env::storage_usage().saturating_sub(initial_storage);
+        let charged = self.internal_charge_store_storage(&review.store_contract_id, bytes);
+        if let Some(mut t) = self.internal_get_transaction(id) {
+            t.store_storage_bytes += charged;
+            self.internal_update_transaction(&mut t);
+        }
+        emit_event("review_replied", json!({
+            "transaction_id": review.transaction_id,
+            "store_contract_id": review.store_contract_id,
+        }));
+    }
+
+    pub fn get_review(&self, transaction_id: U128) -> Option<Review> {
+        self.reviews.get(&transaction_id.into())
+    }
+
+    pub fn get_store_reviews(&self, store_contract_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<Review> {
+        match self.store_reviews.get(&store_contract_id) {
+            Some(review_ids) => review_ids
+                .iter()
+                .skip(u128::from(from_index.unwrap_or(U128(0))) as usize)
+                .take(limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize)
+                .filter_map(|id| self.reviews.get(&id))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn get_store_reputation(&self, store_contract_id: AccountId) -> StoreReputation {
+        self.store_reputations.get(&store_contract_id).unwrap_or_default()
+    }
+
+    pub fn get_return_policy(&self, store_contract_id: AccountId) -> Option<ReturnPolicy> {
+        self.return_policies.get(&store_contract_id)
+    }
+
+    pub fn set_return_policy(&mut self, store_contract_id: AccountId, policy: Option<ReturnPolicy>) -> Promise {
+        if let Some(policy) = policy.as_ref() {
+            assert!(
+                policy.restocking_fee_bps as u128 <= BASIS_POINTS,
+                "Restocking fee cannot exceed {} basis points",
+                BASIS_POINTS
+            );
+            // Returns only settle once the store has burned the returned tokens.
+            self.internal_assert_store_entry_point(&store_contract_id, "burn_token");
+        }
+        let args = serde_json::to_vec(&EmptyData {})
+            .unwrap();
+        Promise::new(store_contract_id.clone())
+            .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .set_return_policy_callback(store_contract_id, policy),
+            )
+    }
+
+    #[private]
+    pub fn set_return_policy_callback(&mut self, store_contract_id: AccountId, policy: Option<ReturnPolicy>) {
+        Self::internal_assert_store_owner_result("Only the store owner can set the return policy");
+
+        // Orders keep the policy they were placed under.
+        match policy.as_ref() {
+            Some(policy) => self.return_policies.insert(&store_contract_id, policy),
+            None => self.return_policies.remove(&store_contract_id),
+        };
+        emit_event("return_policy_updated", json!({ "store_contract_id": store_contract_id, "policy": policy }));
+    }
+
+    pub fn request_return(&mut self, transaction_id: U128, store_contract_id: AccountId, reason: String) {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&env::predecessor_account_id()),
+            TransactionStatus::Delivered,
+        ) {
+            Some(mut t) => {
+                let deadline: u64 = t.return_deadline.expect("This order is not eligible for returns").into();
+                assert!(env::block_timestamp() < deadline, "The return window for this order has closed");
+                assert!(t.escrow_balance() > 0, "The funds for this order have already been released");
+                t.status = TransactionStatus::ReturnRequested;
+                t.return_request = Some(ReturnRequest {
+                    reason,
+                    requested_at: U64::from(env::block_timestamp()),
+                    shipped_at: None,
+                    ipfs: None,
+                    received_at: None,
+                    buyer_refund: None,
+                    seller_payout: None,
+                });
+                self.internal_update_transaction(&mut t);
+                emit_order_event("order_return_requested", &t);
+                env::log_str("Return has been requested")
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
+    pub fn mark_return_shipped(&mut self, transaction_id: U128, store_contract_id: AccountId, ipfs: String) {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&env::predecessor_account_id()),
+            TransactionStatus::ReturnRequested,
+        ) {
+            Some(mut t) => {
+                let request = t.return_request.as_mut().expect("Return request not found");
+                request.shipped_at = Some(U64::from(env::block_timestamp()));
+                request.ipfs = Some(ipfs);
+                self.internal_update_transaction(&mut t);
+                emit_order_event("order_return_shipped", &t);
+                env::log_str("Return has been marked shipped")
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
+    pub fn confirm_return_received(&mut self, transaction_id: U128, buyer_id: AccountId, store_contract_id: AccountId) -> Promise {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&buyer_id),
+            TransactionStatus::ReturnRequested,
+        ) {
+            Some(t) => {
+                self.internal_assert_store_entry_point(&t.store_contract_id, "burn_token");
+                let args = serde_json::to_vec(&EmptyData {})
+                    .unwrap();
+                Promise::new(t.store_contract_id.clone())
+                    .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .confirm_return_received_callback(t.transaction_id),
+                    )
             }
             None => panic!("Transaction not found"),
         }
     }
 
+    #[private]
+    pub fn confirm_return_received_callback(&mut self, transaction_id: U128) -> Promise {
+        Self::internal_assert_store_owner_result("Only the store owner can confirm a return");
+
+        let t = self.internal_get_transaction(transaction_id.into()).expect("Transaction not found");
+        assert_eq!(t.status, TransactionStatus::ReturnRequested, "Transaction is no longer awaiting a return");
+        self.internal_burn_tokens(&t.store_contract_id, &t.token_ids)
+            .then(
+                Self::ext(env::current_account_id())
+                    .confirm_return_burn_callback(t.transaction_id),
+            )
+    }
+
+    // As with cancellations, the return is only settled once every returned token has been
+    // burned; otherwise the order stays awaiting its return.
+    #[private]
+    pub fn confirm_return_burn_callback(&mut self, transaction_id: U128) {
+        let mut t = self.internal_get_transaction(transaction_id.into()).expect("Transaction not found");
+        if !all_promises_succeeded() {
+            emit_order_event("order_return_failed", &t);
+            env::log_str("The store could not burn the returned tokens, the return stays open");
+            return;
+        }
+        if t.status != TransactionStatus::ReturnRequested {
+            env::log_str("Transaction is no longer awaiting a return");
+            return;
+        }
+        let policy = t.return_policy.clone().expect("This order is not eligible for returns");
+        let held = t.escrow_balance();
+        let mut seller_payout = bps_of(held, policy.restocking_fee_bps as u128);
+        if policy.return_shipping_paid_by == ReturnShippingPayer::Buyer {
+            seller_payout += u128::from(policy.return_shipping_fee);
+        }
+        let seller_payout = seller_payout.min(held);
+        let buyer_refund = held - seller_payout;
+
+        t.status = TransactionStatus::Returned;
+        t.released_value = t.buyer_value_locked;
+        if let Some(request) = t.return_request.as_mut() {
+            request.received_at = Some(U64::from(env::block_timestamp()));
+            request.buyer_refund = Some(U128::from(buyer_refund));
+            request.seller_payout = Some(U128::from(seller_payout));
+        }
+        self.internal_update_transaction(&mut t);
+
+        let mut payouts = vec![Payout {
+            receiver_id: t.buyer_id.clone(),
+            amount: U128::from(buyer_refund),
+            kind: PayoutKind::Buyer,
+        }];
+        if seller_payout > 0 {
+            payouts.extend(self.internal_release_payouts(&t, seller_payout));
+        }
+        self.internal_execute_payouts(&t, &payouts);
+        emit_order_event("order_returned", &t);
+        env::log_str("Return received, refunding the buyer")
+    }
+
+    pub fn release_held_funds(&mut self, transaction_id: U128, store_contract_id: AccountId) {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            None,
+            TransactionStatus::Delivered,
+        ) {
+            Some(mut t) => {
+                let deadline: u64 = t.return_deadline.expect("This order has no return window").into();
+                assert!(env::block_timestamp() >= deadline, "Return window is still open, please try again later");
+                let held = t.escrow_balance();
+                assert!(held > 0, "The funds for this order have already been released");
+                t.released_value = t.buyer_value_locked;
+                self.internal_update_transaction(&mut t);
+                let payouts = self.internal_release_payouts(&t, held);
+                self.internal_execute_payouts(&t, &payouts);
+                emit_order_event("order_funds_released", &t);
+                env::log_str("Return window has closed, releasing funds to the seller")
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
+    pub fn request_cancellation(&mut self, transaction_id: U128, store_contract_id: AccountId) {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&env::predecessor_account_id()),
+            TransactionStatus::Approved,
+        ) {
+            Some(mut t) => {
+                assert!(!t.cancellation_requested, "Cancellation has already been requested for this transaction");
+                // A cancellation is only refunded once the store has burned the order's tokens.
+                self.internal_assert_store_entry_point(&t.store_contract_id, "burn_token");
+                t.cancellation_requested = true;
+                self.internal_update_transaction(&mut t);
+                emit_order_event("order_cancellation_requested", &t);
+                env::log_str("Cancellation has been requested, awaiting seller approval")
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
+    pub fn cancel_purchase(&mut self, transaction_id: U128, buyer_id: AccountId, store_contract_id: AccountId) -> Promise {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&buyer_id),
+            TransactionStatus::Approved,
+        ) {
+            Some(t) => {
+                self.internal_assert_store_entry_point(&t.store_contract_id, "burn_token");
+                let args = serde_json::to_vec(&EmptyData {})
+                    .unwrap();
+                Promise::new(t.store_contract_id.clone())
+                    .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .cancel_purchase_callback(t.transaction_id),
+                    )
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
+    #[private]
+    pub fn cancel_purchase_callback(&mut self, transaction_id: U128) -> Promise {
+        Self::internal_assert_store_owner_result("Transaction cancellation failed, please try again");
+        match self.internal_get_transaction(transaction_id.into()) {
+            Some(t) => {
+                assert_eq!(t.status, TransactionStatus::Approved, "Only approved transactions can be canceled");
+                self.internal_burn_tokens(&t.store_contract_id, &t.token_ids)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(Gas(20 * TGAS))
+                            .cancel_purchase_burn_callback(t.transaction_id),
+                    )
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
+    // The buyer is only refunded once the store has confirmed that every token of the
+    // order was burned, otherwise they would keep the tokens as well as the money.
+    #[private]
+    pub fn cancel_purchase_burn_callback(&mut self, transaction_id: U128) {
+        let mut t = self.internal_get_transaction(transaction_id.into()).expect("Transaction not found");
+        if !all_promises_succeeded() {
+            emit_order_event("order_cancellation_failed", &t);
+            env::log_str("The store could not burn the order's tokens, the order stays open");
+            return;
+        }
+        if t.status != TransactionStatus::Approved {
+            env::log_str("Transaction is no longer awaiting cancellation");
+            return;
+        }
+        let requested = t.cancellation_requested;
+        let refund = self.internal_refund_payouts(&t);
+        t.status = TransactionStatus::Canceled;
+        t.released_value = t.buyer_value_locked;
+        self.internal_update_transaction(&mut t);
+
+        self.internal_execute_payouts(&t, &refund);
+        emit_order_event("order_canceled", &t);
+        if requested {
+            env::log_str("Buyer cancellation accepted, returning funds to the buyer")
+        } else {
+            env::log_str("Transaction canceled by seller, returning funds to the buyer")
+        }
+    }
+
+    pub fn get_refund(&mut self, transaction_id: U128, store_contract_id: AccountId) {
+        match self.internal_find_transaction(
+            transaction_id,
+            &store_contract_id,
+            Some(&env::predecessor_account_id()),
+            TransactionStatus::Approved,
+        ) {
+            Some(mut t) => {
+                let timeout = self.calculate_timeout(t.timeout, U128::from(u64::from(t.created_at) as u128));
+                let current_timestamp = env::block_timestamp() as u128;
+
+                if current_timestamp >= timeout {
+                    let refund = self.internal_refund_payouts(&t);
+                    t.status = TransactionStatus::Canceled;
+                    t.released_value = t.buyer_value_locked;
+                    self.internal_update_transaction(&mut t);
+                    env::log_str("Transaction time has elapsed, returning funds to the buyer");
+                    self.internal_execute_payouts(&t, &refund);
+                    emit_order_event("order_refunded", &t);
+                } else {
+                    panic!("Transaction time is yet to elapsed, please try again later")
+                }
+            }
+            None => panic!("Transaction not found"),
+        }
+    }
+
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let min_balance: u128 = self.storage_balance_bounds().min.into();
+        let account = match self.storage_accounts.get(&account_id) {
+            Some(mut account) => {
+                if registration_only.unwrap_or(false) {
+                    if amount > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(amount);
+                    }
+                } else {
+                    account.deposit += amount;
+                }
+                account
+            }
+            None => {
+                assert!(amount >= min_balance, "The attached deposit is less than the minimum storage balance");
+                let deposit = if registration_only.unwrap_or(false) {
+                    if amount > min_balance {
+                        Promise::new(env::predecessor_account_id()).transfer(amount - min_balance);
+                    }
+                    min_balance
+                } else {
+                    amount
+                };
+                StorageAccount { deposit, used_bytes: 0 }
+            }
+        };
+        self.storage_accounts.insert(&account_id, &account);
+        self.internal_storage_balance(&account)
+    }
+
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self.storage_accounts.get(&account_id).expect("The account is not registered");
+        let available: u128 = self.internal_storage_balance(&account).available.into();
+        let amount: u128 = amount.map_or(available, |amount| amount.into());
+        assert!(amount <= available, "The amount is greater than the available storage balance");
+        if amount > 0 {
+            account.deposit -= amount;
+            self.storage_accounts.insert(&account_id, &account);
+            Promise::new(account_id).transfer(amount);
+        }
+        self.internal_storage_balance(&account)
+    }
+
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        match self.storage_accounts.get(&account_id) {
+            Some(account) => {
+                // Orders cannot be dropped on the account's behalf, so `force` does not override this.
+                let _ = force;
+                assert_eq!(account.used_bytes, 0, "Cannot unregister while orders hold storage, prune settled orders first");
+                self.storage_accounts.remove(&account_id);
+                Promise::new(account_id).transfer(account.deposit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(|account| self.internal_storage_balance(&account))
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128::from(STORAGE_REGISTRATION_BYTES as u128 * env::storage_byte_cost()),
+            max: None,
+        }
+    }
+
+    // Settled orders can be dropped by their buyer or the store's owner, which hands all of
+    // the order's storage back to whoever paid for it. The store's reputation keeps the rating.
+    pub fn prune_transaction(&mut self, transaction_id: U128) {
+        let t = self.internal_get_transaction(transaction_id.into()).expect("Transaction not found");
+        let caller = env::predecessor_account_id();
+        let store_owner_id = self.internal_order_store_owner(&t);
+        assert!(
+            caller == t.buyer_id || store_owner_id == Some(caller),
+            "Only the buyer or the store owner can prune an order"
+        );
+        assert!(!t.holds_escrow(), "Only settled orders can be pruned");
+        // Removing orders reorders the map the V1 migration walks by index.
+        assert!(self.pending_migration.is_none(), "Orders are still being migrated, please try again later");
+        self.internal_remove_transaction(&t);
+        self.internal_release_storage(&t.buyer_id, t.buyer_storage_bytes);
+        if let Some(payer_id) = store_owner_id.filter(|owner_id| self.storage_accounts.get(owner_id).is_some()) {
+            self.internal_release_storage(&payer_id, t.store_storage_bytes);
+        }
+        emit_order_event("order_pruned", &t);
+    }
+
+    pub fn close_store(&mut self, prefix: String) -> Promise {
+        assert!(self.stores.contains(&prefix), "Store {} not found", prefix);
+        let store_id: AccountId = format!("{}.{}", prefix, env::current_account_id()).parse().unwrap();
+        self.internal_assert_store_entry_point(&store_id, "close_store");
+        self.internal_assert_no_open_orders(&store_id);
+
+        let args = serde_json::to_vec(&EmptyData {})
+            .unwrap();
+        Promise::new(store_id)
+            .function_call("assert_store_owner".to_owned(), args, NO_DEPOSIT, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .close_store_owner_callback(prefix, env::signer_account_id()),
+            )
+    }
+
+    #[private]
+    pub fn close_store_owner_callback(&mut self, prefix: String, beneficiary_id: AccountId) -> Promise {
+        Self::internal_assert_store_owner_result("Only the store owner can close this store");
+
+        let store_id: AccountId = format!("{}.{}", prefix, env::current_account_id()).parse().unwrap();
+        // Orders may have been placed while ownership was being checked.
+        self.internal_assert_no_open_orders(&store_id);
+        let args = serde_json::to_vec(&Beneficiary {
+            beneficiary_id: beneficiary_id.clone(),
+        })
+            .unwrap();
+        // The factory holds no keys on the store account, so the store deletes itself.
+        Promise::new(store_id)
+            .function_call("close_store".to_owned(), args, NO_DEPOSIT, PGAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(10 * TGAS))
+                    .close_store_callback(prefix, beneficiary_id),
+            )
+    }
+
+    #[private]
+    pub fn close_store_callback(&mut self, prefix: String, beneficiary_id: AccountId) {
+        let store_id: AccountId = format!("{}.{}", prefix, env::current_account_id()).parse().unwrap();
+        if is_promise_success() {
+            self.internal_archive_store(&store_id, &beneficiary_id);
+            emit_event("store_closed", json!({
+                "store_id": store_id,
+                "prefix": prefix,
+                "beneficiary_id": beneficiary_id,
+            }));
+            env::log_str("Store closed and balance returned to the owner")
+        } else {
+            emit_event("store_close_failed", json!({ "store_id": store_id, "prefix": prefix }));
+            env::log_str("failed to close store")
+        }
+    }
+
+}
+
+impl PiparContractFactory {
+    // Every field as a freshly deployed factory has it, without writing anything to storage.
+    pub(crate) fn internal_default_state(owner_id: AccountId) -> Self {
+        Self {
+            stores: LookupSet::new(b"s".to_vec()),
+            transactions: UnorderedMap::new(StorageKey::Transactions),
+            store_cost: U128::from(STORE_BALANCE),
+            stores_stats: UnorderedMap::new(b"w".to_vec()),
+            transactions_by_buyer: LookupMap::new(StorageKey::TransactionsByBuyer),
+            transactions_by_store: LookupMap::new(StorageKey::TransactionsByStore),
+            open_purchases: LookupMap::new(StorageKey::OpenPurchases),
+            order_references: LookupMap::new(StorageKey::OrderReferences),
+            next_transaction_id: 1,
+            accepted_tokens: UnorderedSet::new(StorageKey::AcceptedTokens),
+            owner_id: owner_id.clone(),
+            roles: LookupMap::new(StorageKey::Roles),
+            paused: false,
+            store_code_versions: UnorderedMap::new(StorageKey::StoreCodeVersions),
+            store_code: LookupMap::new(StorageKey::StoreCode),
+            default_store_code_hash: String::new(),
+            bundled_store_code_hash: String::new(),
+            confirmation_window_days: DEFAULT_CONFIRMATION_WINDOW_DAYS,
+            platform_fee_bps: DEFAULT_PLATFORM_FEE_BPS,
+            category_fees: UnorderedMap::new(StorageKey::CategoryFees),
+            fee_balances: UnorderedMap::new(StorageKey::FeeBalances),
+            treasury_id: owner_id,
+            referral_fee_bps: DEFAULT_REFERRAL_FEE_BPS,
+            store_registry: UnorderedMap::new(StorageKey::StoreRegistry),
+            stores_by_owner: LookupMap::new(StorageKey::StoresByOwner),
+            reserved_names: UnorderedSet::new(StorageKey::ReservedNames),
+            archived_stores: UnorderedMap::new(StorageKey::ArchivedStores),
+            released_prefixes: LookupMap::new(StorageKey::ReleasedPrefixes),
+            store_name_cooldown_days: DEFAULT_STORE_NAME_COOLDOWN_DAYS,
+            keypom_contracts: UnorderedSet::new(StorageKey::KeypomContracts),
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            store_creation_fee: U128(0),
+            next_cart_id: 1,
+            cart_transactions: LookupMap::new(StorageKey::CartTransactions),
+            return_policies: LookupMap::new(StorageKey::ReturnPolicies),
+            tracking: LookupMap::new(StorageKey::Tracking),
+            reviews: LookupMap::new(StorageKey::Reviews),
+            store_reviews: LookupMap::new(StorageKey::StoreReviews),
+            store_reputations: LookupMap::new(StorageKey::StoreReputations),
+            claimable_balances: LookupMap::new(StorageKey::ClaimableBalances),
+            open_escrow_counts: LookupMap::new(StorageKey::OpenEscrowCounts),
+            pending_migration: None,
+            locked_escrow: 0,
+            archived_store_transactions: LookupMap::new(StorageKey::ArchivedStoreTransactions),
+            archived_store_reviews: LookupMap::new(StorageKey::ArchivedStoreReviews),
+        }
+    }
+
+    pub(crate) fn internal_register_defaults(&mut self) {
+        self.internal_register_bundled_store_code();
+        for name in DEFAULT_RESERVED_NAMES {
+            self.reserved_names.insert(&name.to_string());
+        }
+    }
+
+    // Legacy orders are copied over by `migrate_transactions`; their ids are reserved here
+    // so they keep their original order however the batches are run. Store stats were never
+    // written before, so the default map is reused under the legacy prefix.
+    pub(crate) fn internal_migrate_legacy_state() -> Self {
+        let old: LegacyPiparContractFactory = env::state_read().expect("Failed to read contract state");
+        let mut contract = Self {
+            stores: old.stores,
+            store_cost: old.store_cost,
+            next_transaction_id: old.transactions.len() as u128 + 1,
+            pending_migration: if old.transactions.is_empty() {
+                None
+            } else {
+                Some(PendingMigration::Legacy { transactions: old.transactions })
+            },
+            ..Self::internal_default_state(env::current_account_id())
+        };
+        contract.internal_register_defaults();
+
+        contract
+    }
+
+    // V1 orders stay readable through `VersionedTransaction`; `migrate_transactions` only
+    // has to count them towards the per-store counters V1 did not keep.
+    pub(crate) fn internal_migrate_v1_state() -> Self {
+        let old: PiparContractFactoryV1 = env::state_read().expect("Failed to read contract state");
+        let end_index = old.transactions.len();
+        let mut contract = Self {
+            stores: old.stores,
+            transactions: old.transactions,
+            store_cost: old.store_cost,
+            transactions_by_buyer: old.transactions_by_buyer,
+            transactions_by_store: old.transactions_by_store,
+            open_purchases: old.open_purchases,
+            order_references: old.order_references,
+            next_transaction_id: old.next_transaction_id,
+            accepted_tokens: old.accepted_tokens,
+            roles: old.roles,
+            paused: old.paused,
+            pending_migration: if end_index == 0 {
+                None
+            } else {
+                Some(PendingMigration::V1 { next_index: 0, end_index })
+            },
+            ..Self::internal_default_state(old.owner_id)
+        };
+        contract.internal_register_defaults();
+
+        contract
+    }
+
+    // Orders shipped before the release window existed get a full window from the migration.
+    pub(crate) fn internal_migrated_transaction(&self, t: TransactionV1) -> Transaction {
+        let mut t = Transaction::from(t);
+        if t.status == TransactionStatus::Shipped {
+            t.confirmation_deadline = Some(U64::from(self.calculate_timeout(
+                U128::from(self.confirmation_window_days as u128),
+                U128::from(env::block_timestamp() as u128),
+            ) as u64));
+        }
+        t
+    }
+
+    pub(crate) fn internal_register_bundled_store_code(&mut self) {
+        let code_hash = hex_encode(&env::sha256(BUNDLED_STORE_CODE));
+        self.store_code_versions.insert(&code_hash, &StoreCodeVersion {
+            version: self.store_code_versions.len() as u32 + 1,
+            code_hash: code_hash.clone(),
+            release_notes: String::from("Store code bundled with the factory"),
+            is_default: true,
+            created_at: U64::from(env::block_timestamp()),
+            entry_points: Self::store_entry_points(BUNDLED_STORE_CODE),
+        });
+        self.bundled_store_code_hash = code_hash.clone();
+        self.default_store_code_hash = code_hash;
+    }
+
+    pub(crate) fn internal_store_code(&self, code_hash: &String) -> Vec<u8> {
+        if code_hash == &self.bundled_store_code_hash {
+            BUNDLED_STORE_CODE.to_vec()
+        } else {
+            self.store_code.get(code_hash).expect("Store code not found")
+        }
+    }
+
+    pub(crate) fn internal_set_default_store_code(&mut self, code_hash: &String) {
+        if let Some(mut previous) = self.store_code_versions.get(&self.default_store_code_hash) {
+            previous.is_default = false;
+            self.store_code_versions.insert(&self.default_store_code_hash, &previous);
+        }
+        let mut version = self.store_code_versions.get(code_hash).expect("Store code not found");
+        version.is_default = true;
+        self.store_code_versions.insert(code_hash, &version);
+        self.default_store_code_hash = code_hash.clone();
+        emit_event("default_store_code_updated", json!({ "code_hash": code_hash }));
+    }
+
+    pub(crate) fn store_entry_points(code: &[u8]) -> Vec<String> {
+        let exports = wasm_exported_functions(code).expect("Store code is not a valid wasm module");
+        STORE_ENTRY_POINTS
+            .iter()
+            .filter(|entry_point| exports.iter().any(|name| name == *entry_point))
+            .map(|entry_point| entry_point.to_string())
+            .collect()
+    }
+
+    pub(crate) fn internal_assert_store_entry_point(&self, store_id: &AccountId, entry_point: &str) {
+        assert!(self.store_registry.get(store_id).is_some(), "Store {} is not registered", store_id);
+        assert!(
+            self.internal_store_supports(store_id, entry_point),
+            "Store {} runs code without the {} entry point",
+            store_id,
+            entry_point
+        );
+    }
+
+    pub(crate) fn internal_store_supports(&self, store_id: &AccountId, entry_point: &str) -> bool {
+        let code_version = self.store_registry
+            .get(store_id)
+            .and_then(|store| self.store_code_versions.get(&store.code_hash));
+        match code_version {
+            Some(version) => version.entry_points.iter().any(|name| name == entry_point),
+            None => false,
+        }
+    }
+
+    // Several units are minted in one call and must be burned again if the order is
+    // rejected, so only stores that can burn sell more than one unit per order.
+    pub(crate) fn internal_assert_order_quantity(&self, store_id: &AccountId, quantity: u32) {
+        assert_order_quantity(quantity);
+        assert!(
+            quantity == 1 || self.internal_store_supports(store_id, "burn_token"),
+            "Store {} only sells one unit per order",
+            store_id
+        );
+    }
+
+    // Store contracts never call the factory themselves, so the storage their orders and
+    // replies take up is charged to the storage balance of the store's owner. Stores that
+    // are not registered, or whose owner holds no storage balance, are covered by the factory.
+    pub(crate) fn internal_store_storage_payer(&self, store_id: &AccountId) -> Option<AccountId> {
+        let owner_id = self.internal_store_owner(store_id)?;
+        self.storage_accounts.get(&owner_id).map(|_| owner_id)
+    }
+
+    pub(crate) fn internal_store_owner(&self, store_id: &AccountId) -> Option<AccountId> {
+        match self.store_registry.get(store_id) {
+            Some(store) => Some(store.owner_id),
+            None => self
+                .archived_stores
+                .get(store_id)
+                .and_then(|history| history.last().map(|archived| archived.store.owner_id.clone())),
+        }
+    }
+
+    // Owner of the store an order was placed with, which may have been closed since.
+    pub(crate) fn internal_order_store_owner(&self, t: &Transaction) -> Option<AccountId> {
+        match self.internal_closed_store_generation(t) {
+            Some(generation) => self
+                .archived_stores
+                .get(&t.store_contract_id)
+                .map(|history| history[generation as usize].store.owner_id.clone()),
+            None => self.internal_store_owner(&t.store_contract_id),
+        }
+    }
+
+    pub(crate) fn internal_assert_store_storage_available(&self, store_id: &AccountId, bytes: u64) {
+        if let Some(payer_id) = self.internal_store_storage_payer(store_id) {
+            self.internal_assert_storage_available(&payer_id, bytes);
+        }
+    }
+
+    // Returns the bytes that were charged to the store's owner.
+    pub(crate) fn internal_charge_store_storage(&mut self, store_id: &AccountId, bytes: u64) -> u64 {
+        match self.internal_store_storage_payer(store_id) {
+            Some(payer_id) => {
+                self.internal_charge_storage(&payer_id, bytes);
+                bytes
+            }
+            None => 0,
+        }
+    }
+
+    // Stores replace their own code through an `upgrade` entry point that takes the new
+    // code as raw input, deploys it and runs its migration. Only stores running code that
+    // exports it can be upgraded; the bundled store code does not.
+    pub(crate) fn internal_upgrade_store(&self, prefix: &String, code_hash: &str, code: Vec<u8>) -> Promise {
+        let store_id: AccountId = format!("{}.{}", prefix, env::current_account_id()).parse().unwrap();
+        let gas = store_upgrade_gas(code.len());
+        Promise::new(store_id.clone())
+            .function_call("upgrade".to_owned(), code, NO_DEPOSIT, gas)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(UPGRADE_STORE_CALLBACK_GAS)
+                    .upgrade_store_callback(store_id, code_hash.to_string()),
+            )
+    }
+
+    // Callbacks of the store's `assert_store_owner` check panic with `msg` unless the
+    // caller was confirmed as the owner.
+    pub(crate) fn internal_assert_store_owner_result(msg: &str) {
+        assert_eq!(env::promise_results_count(), 1, "ERR_TOO_MANY_RESULTS");
+        let is_owner = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(val) => serde_json::from_slice::<bool>(&val).unwrap_or(false),
+            PromiseResult::Failed => false,
+        };
+        if !is_owner {
+            env::panic_str(msg)
+        }
+    }
+
+    pub(crate) fn internal_write_state_version() {
+        env::storage_write(STATE_VERSION_KEY, &CURRENT_STATE_VERSION.try_to_vec().unwrap());
+    }
+
+    pub(crate) fn internal_get_transaction(&self, transaction_id: u128) -> Option<Transaction> {
+        self.transactions.get(&transaction_id).map(Transaction::from)
+    }
+
+    pub(crate) fn internal_assert_can_manage_role(&self, role: Role) {
+        let caller = env::predecessor_account_id();
+        let allowed = match role {
+            Role::Admin => caller == self.owner_id,
+            _ => self.has_role(caller, Role::Admin),
+        };
+        assert!(allowed, "Not allowed to manage the {:?} role", role);
+    }
+
+    fn open_purchase_key(buyer_id: &AccountId, store_contract_id: &AccountId, product_id: U64) -> String {
+        format!("{}:{}:{}", buyer_id, store_contract_id, u64::from(product_id))
+    }
+
+    pub(crate) fn internal_assert_no_open_purchase(&self, buyer_id: &AccountId, store_contract_id: &AccountId, product_id: U64) {
+        let key = Self::open_purchase_key(buyer_id, store_contract_id, product_id);
+        if let Some(transaction_id) = self.open_purchases.get(&key) {
+            panic!(
+                "Cannot escrow buy twice on the same product with the same seller, you must complete one first: {:?}",
+                self.internal_get_transaction(transaction_id)
+            )
+        }
+    }
+
+    pub(crate) fn internal_next_transaction_id(&mut self) -> u128 {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id += 1;
+        transaction_id
+    }
+
+    pub(crate) fn internal_order_reference(&self, transaction_id: u128) -> String {
+        let mut seed = env::random_seed();
+        seed.extend_from_slice(&transaction_id.to_le_bytes());
+        let suffix = hex_encode(&env::sha256(&seed)[..3]).to_uppercase();
+        format!("PIP-{:06}-{}", transaction_id, suffix)
+    }
+
+    pub(crate) fn internal_record_purchase(&mut self, result: &MarketplaceData, purchase: PendingPurchase) -> Transaction {
+        let PendingPurchase {
+            buyer_id,
+            amount,
+            product_id,
+            store_contract_id,
+            timeout,
+            is_discount,
+            is_reward,
+            is_keypom,
+            hashed_billing_address,
+            nonce,
+            ft_token_id,
+            referrer_id,
+            cart_id,
+            quantity,
+            storage_deposit,
+        } = purchase;
+        let referrer_id = referrer_id.filter(|referrer_id| referrer_id != &buyer_id);
+        let mut royalties: Vec<(AccountId, u32)> = result.royalty.clone().into_iter().collect();
+        royalties.sort();
+        let platform_fee_bps = self.internal_platform_fee_bps(&store_contract_id);
+        let return_policy = self.return_policies.get(&store_contract_id).filter(|policy| policy.window_days > 0);
+        let transaction_id = self.internal_next_transaction_id();
+        let transaction = Transaction {
+            transaction_id: U128::from(transaction_id),
+            order_reference: self.internal_order_reference(transaction_id),
+            created_at: U64::from(env::block_timestamp()),
+            product_id,
+            store_contract_id,
+            buyer_id,
+            buyer_value_locked: amount,
+            price: result.price,
+            token_id: result.token_id.clone(),
+            timeout,
+            affiliate: result.affiliate,
+            affiliate_id: result.affiliate_id.clone(),
+            affiliate_percentage: result.affiliate_percentage,
+            is_discount,
+            is_reward,
+            is_keypom,
+            status: TransactionStatus::Approved,
+            hashed_billing_address,
+            nonce,
+            ipfs: String::from(""),
+            dispute_resolution: None,
+            cancellation_requested: false,
+            ft_token_id,
+            shipped_at: None,
+            confirmation_deadline: None,
+            platform_fee_bps,
+            referral_fee_bps: if referrer_id.is_some() { self.referral_fee_bps } else { 0 },
+            referrer_id,
+            royalties,
+            buyer_storage_bytes: 0,
+            store_storage_bytes: 0,
+            cart_id,
+            quantity,
+            token_ids: result.minted_token_ids(),
+            shipped_quantity: 0,
+            delivered_quantity: 0,
+            released_value: U128(0),
+            return_policy,
+            return_deadline: None,
+            return_request: None,
+        };
+        let initial_storage = env::storage_usage();
+        self.internal_add_transaction(&transaction);
+        let mut transaction = transaction;
+        let bytes = env::storage_usage().saturating_sub(initial_storage);
+        let storage_deposit: u128 = storage_deposit.into();
+        if storage_deposit > 0 {
+            let surplus = storage_deposit.saturating_sub(bytes as u128 * env::storage_byte_cost());
+            if surplus > 0 {
+                Promise::new(transaction.buyer_id.clone()).transfer(surplus);
+                emit_payout_event(None, &transaction.buyer_id, surplus, "storage_refund");
+            }
+        } else {
+            transaction.buyer_storage_bytes = bytes;
+            self.transactions.insert(&transaction_id, &VersionedTransaction::from(transaction.clone()));
+            self.internal_charge_storage(&transaction.buyer_id, transaction.buyer_storage_bytes);
+        }
+        emit_order_event("order_created", &transaction);
+        transaction
+    }
+
+    // Tokens minted for an order that cannot be accepted are burned before the buyer is
+    // refunded. If the store cannot burn them the buyer keeps the tokens, so the payment
+    // is held as a disputed order for an arbiter to settle instead.
+    pub(crate) fn internal_reject_mint(&self, result: MarketplaceData, purchase: PendingPurchase, reason: String) -> Promise {
+        emit_event("purchase_rejected", json!({
+            "store_contract_id": purchase.store_contract_id,
+            "buyer_id": purchase.buyer_id,
+            "token_ids": result.minted_token_ids(),
+            "unit_price": U128::from(result.price),
+            "quantity": purchase.quantity,
+            "amount": purchase.amount,
+            "reason": reason,
+        }));
+        self.internal_burn_tokens(&purchase.store_contract_id, &result.minted_token_ids())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(30 * TGAS))
+                    .reject_mint_callback(result, purchase),
+            )
+    }
+
+    // Stores burn through their `burn_token` entry point; stores running code without it
+    // fail the call, which callers treat as the tokens still existing.
+    pub(crate) fn internal_burn_tokens(&self, store_contract_id: &AccountId, token_ids: &[String]) -> Promise {
+        let mut burns: Option<Promise> = None;
+        for token_id in token_ids {
+            let args = serde_json::to_vec(&Token {
+                token_id: token_id.clone(),
+            })
+                .unwrap();
+            let burn = Promise::new(store_contract_id.clone())
+                .function_call("burn_token".to_owned(), args, NO_DEPOSIT, BURN_TOKEN_GAS);
+            burns = Some(match burns {
+                Some(burns) => burns.and(burn),
+                None => burn,
+            });
+        }
+        burns.expect("There are no tokens to burn")
+    }
+
+    pub(crate) fn internal_platform_fee_bps(&self, store_contract_id: &AccountId) -> u16 {
+        self.get_platform_fee_bps(self.store_registry.get(store_contract_id).map(|store| store.category))
+    }
+
+    pub(crate) fn internal_credit_fee(&mut self, ft_token_id: &Option<AccountId>, amount: u128) {
+        if amount > 0 {
+            let balance = self.fee_balances.get(ft_token_id).unwrap_or(0);
+            self.fee_balances.insert(ft_token_id, &(balance + amount));
+        }
+    }
+
+    // Splits released escrow between the store and every other party. The platform
+    // fee is taken from the full amount and the referrer is paid out of it; affiliate
+    // and royalty shares come out of the store's part, which absorbs all rounding so
+    // the payouts always add up to `amount` exactly.
+    pub(crate) fn internal_release_payouts(&self, t: &Transaction, amount: u128) -> Vec<Payout> {
+        let platform_fee = bps_of(amount, t.platform_fee_bps as u128);
+        let referrer_fee = bps_of(platform_fee, t.referral_fee_bps as u128);
+        let seller_funds = amount - platform_fee;
+
+        let mut payouts = vec![];
+        let mut store_funds = seller_funds;
+        if let (true, Some(affiliate_id)) = (t.affiliate, t.affiliate_id.clone()) {
+            let affiliate_bps = t.affiliate_percentage.unwrap_or(0) as u128 * 100;
+            let affiliate_payout = bps_of(seller_funds, affiliate_bps);
+            store_funds = store_funds.checked_sub(affiliate_payout).expect("Affiliate share exceeds the seller funds");
+            payouts.push(Payout { receiver_id: affiliate_id, amount: U128::from(affiliate_payout), kind: PayoutKind::Affiliate });
+        }
+        for (receiver_id, royalty_bps) in t.royalties.iter() {
+            let royalty_payout = bps_of(seller_funds, *royalty_bps as u128);
+            store_funds = store_funds.checked_sub(royalty_payout).expect("Royalty shares exceed the seller funds");
+            payouts.push(Payout { receiver_id: receiver_id.clone(), amount: U128::from(royalty_payout), kind: PayoutKind::Royalty });
+        }
+        if let Some(referrer_id) = t.referrer_id.clone() {
+            payouts.push(Payout { receiver_id: referrer_id, amount: U128::from(referrer_fee), kind: PayoutKind::Referrer });
+        }
+        payouts.push(Payout {
+            receiver_id: env::current_account_id(),
+            amount: U128::from(platform_fee - referrer_fee),
+            kind: PayoutKind::Platform,
+        });
+        payouts.insert(0, Payout { receiver_id: t.store_contract_id.clone(), amount: U128::from(store_funds), kind: PayoutKind::Store });
+
+        let total: u128 = payouts.iter().map(|p| u128::from(p.amount)).sum();
+        assert_eq!(total, amount, "Payouts must add up to the released amount");
+        payouts
+    }
+
+    pub(crate) fn internal_refund_payouts(&self, t: &Transaction) -> Vec<Payout> {
+        vec![Payout {
+            receiver_id: t.buyer_id.clone(),
+            amount: U128::from(t.escrow_balance()),
+            kind: PayoutKind::Buyer,
+        }]
+    }
+
+    pub(crate) fn internal_execute_payouts(&mut self, t: &Transaction, payouts: &[Payout]) {
+        for payout in payouts {
+            let amount: u128 = payout.amount.into();
+            if amount == 0 {
+                continue;
+            }
+            match (payout.kind, &t.ft_token_id) {
+                (PayoutKind::Platform, _) => self.internal_credit_fee(&t.ft_token_id, amount),
+                (_, Some(ft_token_id)) => {
+                    // The payout event is only emitted once the token contract confirms the transfer.
+                    self.internal_ft_payout(&payout.receiver_id, amount, ft_token_id, Some(t.transaction_id), payout.kind.as_str());
+                    continue;
+                }
+                (_, None) => {
+                    self.internal_transfer(&payout.receiver_id, amount, &None);
+                }
+            }
+            emit_payout_event(Some(t.transaction_id), &payout.receiver_id, amount, payout.kind.as_str());
+        }
+    }
+
+    pub(crate) fn internal_complete_purchase(&self, t: Transaction, units: u32) -> Promise {
+        let callback_gas = Gas(COMPLETE_PURCHASE_GAS.0 + UNLOCK_TOKEN_GAS.0 * t.token_ids.len() as u64);
+        if t.is_reward == true {
+            let args = serde_json::to_vec(&TokenData {
+                id: t.product_id,
+                receiver_id: t.buyer_id,
+            })
+            .unwrap();
+            Promise::new(t.store_contract_id.clone())
+                .function_call("reward_with_token".to_owned(), args, NO_DEPOSIT, PGAS)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(callback_gas)
+                        .complete_purchase_callback(t.transaction_id, units),
+                )
+        } else {
+            Promise::new(env::current_account_id())
+                .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(callback_gas)
+                    .complete_purchase_callback(t.transaction_id, units),
+            )
+        }
+    }
+
+    pub(crate) fn internal_transfer(&self, receiver_id: &AccountId, amount: u128, ft_token_id: &Option<AccountId>) -> Promise {
+        match ft_token_id {
+            Some(ft_token_id) => {
+                let args = serde_json::to_vec(&FtTransfer {
+                    receiver_id: receiver_id.clone(),
+                    amount: U128::from(amount),
+                    memo: None,
+                })
+                    .unwrap();
+                Promise::new(ft_token_id.clone())
+                    .function_call("ft_transfer".to_owned(), args, FT_TRANSFER_DEPOSIT, FT_TRANSFER_GAS)
+            }
+            None => Promise::new(receiver_id.clone()).transfer(amount),
+        }
+    }
+
+    pub(crate) fn internal_ft_payout(
+        &self,
+        receiver_id: &AccountId,
+        amount: u128,
+        ft_token_id: &AccountId,
+        transaction_id: Option<U128>,
+        kind: &str,
+    ) -> Promise {
+        self.internal_transfer(receiver_id, amount, &Some(ft_token_id.clone()))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas(5 * TGAS))
+                    .resolve_ft_payout_callback(
+                        receiver_id.clone(),
+                        U128::from(amount),
+                        ft_token_id.clone(),
+                        transaction_id,
+                        kind.to_string(),
+                    ),
+            )
+    }
+
+    pub(crate) fn internal_find_transaction(
+        &self,
+        transaction_id: U128,
+        store_contract_id: &AccountId,
+        buyer_id: Option<&AccountId>,
+        status: TransactionStatus,
+    ) -> Option<Transaction> {
+        self.internal_get_transaction(transaction_id.into())
+            .filter(|t| {
+                &t.store_contract_id == store_contract_id
+                    && buyer_id.map_or(true, |buyer_id| &t.buyer_id == buyer_id)
+                    && t.status == status
+            })
+    }
+
+    pub(crate) fn internal_assert_store_deposit(&self) -> StoreCreationQuote {
+        let quote = self.quote_store_creation();
+        let total: u128 = quote.total.into();
+        assert!(
+            env::attached_deposit() >= total,
+            "To cover the storage required for your store, you need to attach at least {} yoctoNEAR to this transaction.",
+            total
+        );
+        quote
+    }
+
+    // The registry part of the quote is an upper bound; the creator only pays for the
+    // bytes the record actually took and gets the rest of the deposit back.
+    pub(crate) fn internal_settle_store_creation(
+        &mut self,
+        payer_id: &AccountId,
+        attached_deposit: u128,
+        quote: &StoreCreationQuote,
+        initial_storage: u64,
+    ) {
+        let storage_cost = env::storage_usage().saturating_sub(initial_storage) as u128 * env::storage_byte_cost();
+        let creation_fee: u128 = quote.creation_fee.into();
+        let cost = u128::from(quote.store_balance) + storage_cost + creation_fee;
+        self.internal_credit_fee(&None, creation_fee);
+        let refund = attached_deposit.saturating_sub(cost);
+        if refund > 0 {
+            Promise::new(payer_id.clone()).transfer(refund);
+            emit_payout_event(None, payer_id, refund, "store_creation_surplus_refund");
+        }
+    }
+
+    pub(crate) fn internal_check_store_prefix(&self, prefix: &str) -> Result<(), String> {
+        if prefix.len() < MIN_STORE_PREFIX_LEN || prefix.len() > MAX_STORE_PREFIX_LEN {
+            return Err(format!(
+                "Store name must be between {} and {} characters long",
+                MIN_STORE_PREFIX_LEN, MAX_STORE_PREFIX_LEN
+            ));
+        }
+        if !prefix.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_') {
+            return Err("Store name may only contain lowercase letters, digits, '-' and '_'".to_string());
+        }
+        let store_id = format!("{}.{}", prefix, env::current_account_id());
+        if !env::is_valid_account_id(store_id.as_bytes()) {
+            return Err(format!("{} is not a valid account id", store_id));
+        }
+        if self.reserved_names.contains(&prefix.to_string()) {
+            return Err(format!("Store name {} is reserved, choose another name", prefix));
+        }
+        if self.check_contains_store(prefix.to_string()) {
+            return Err(format!("Store name {} is already taken", prefix));
+        }
+        if let Some(available_at) = self.released_prefixes.get(&prefix.to_string()) {
+            if env::block_timestamp() < available_at {
+                return Err(format!("Store name {} was recently released and cannot be reused yet", prefix));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn internal_store_record(
+        &self,
+        store_id: &AccountId,
+        prefix: &str,
+        ft_data: &FtData,
+        is_keypom: bool,
+    ) -> StoreRecord {
+        StoreRecord {
+            store_id: store_id.clone(),
+            prefix: prefix.to_string(),
+            owner_id: ft_data.owner_id.clone(),
+            name: ft_data.name.clone(),
+            category: ft_data.category.clone(),
+            city: ft_data.city.clone(),
+            state: ft_data.state.clone(),
+            country: ft_data.country.clone(),
+            code_hash: self.default_store_code_hash.clone(),
+            created_at: U64::from(env::block_timestamp()),
+            is_keypom,
+        }
+    }
+
+    pub(crate) fn tracking_update_bytes(update: &TrackingUpdate) -> u64 {
+        let carrier = update.carrier.as_ref().map_or(0, |carrier| carrier.len());
+        let tracking_code_hash = update.tracking_code_hash.as_ref().map_or(0, |hash| hash.len());
+        SHIPPING_STORAGE_BYTES + (carrier + tracking_code_hash + update.ipfs.len()) as u64
+    }
+
+    pub(crate) fn internal_append_tracking(&mut self, t: &Transaction, update: TrackingUpdate) {
+        let transaction_id: u128 = t.transaction_id.into();
+        let mut updates = self.tracking.get(&transaction_id).unwrap_or_else(|| {
+            Vector::new(StorageKey::TrackingUpdates { transaction_id })
+        });
+        updates.push(&update);
+        self.tracking.insert(&transaction_id, &updates);
+    }
+
+    pub(crate) fn internal_assert_no_open_orders(&self, store_id: &AccountId) {
+        assert!(self.pending_migration.is_none(), "Orders are still being migrated, please try again later");
+        assert_eq!(self.get_open_escrow_count(store_id.clone()), 0, "Store still has open orders held in escrow");
+    }
+
+    // Keeps the per-store count of orders that still hold escrow, so closing a store
+    // never has to scan its order history.
+    pub(crate) fn internal_track_open_escrow(&mut self, store_id: &AccountId, was_open: bool, is_open: bool) {
+        if was_open == is_open {
+            return;
+        }
+        let count = self.get_open_escrow_count(store_id.clone());
+        let count = if is_open { count + 1 } else { count.saturating_sub(1) };
+        if count == 0 {
+            self.open_escrow_counts.remove(store_id);
+        } else {
+            self.open_escrow_counts.insert(store_id, &count);
+        }
+    }
+
+    // Keeps the total NEAR held in escrow, so fee withdrawals can never spend buyers' funds.
+    pub(crate) fn internal_track_locked_escrow(&mut self, t: &Transaction, previous_balance: u128) {
+        if t.ft_token_id.is_none() {
+            self.locked_escrow = self.locked_escrow + t.escrow_balance() - previous_balance;
+        }
+    }
+
+    pub(crate) fn internal_archive_store(&mut self, store_id: &AccountId, closed_by: &AccountId) {
+        if let Some(store) = self.store_registry.remove(store_id) {
+            if let Some(mut owner_stores) = self.stores_by_owner.get(&store.owner_id) {
+                owner_stores.remove(store_id);
+                if owner_stores.is_empty() {
+                    self.stores_by_owner.remove(&store.owner_id);
+                } else {
+                    self.stores_by_owner.insert(&store.owner_id, &owner_stores);
+                }
+            }
+            self.stores.remove(&store.prefix);
+            let available_at = self.calculate_timeout(
+                U128::from(self.store_name_cooldown_days as u128),
+                U128::from(env::block_timestamp() as u128),
+            ) as u64;
+            self.released_prefixes.insert(&store.prefix, &available_at);
+
+            // The name can be registered again, so this store's orders, reviews and counters
+            // are archived under its registration and the new store starts from scratch.
+            let generation = self.internal_store_generation(store_id);
+            if let Some(transaction_ids) = self.transactions_by_store.remove(store_id) {
+                self.archived_store_transactions.insert(&(store_id.clone(), generation), &transaction_ids);
+            }
+            if let Some(review_ids) = self.store_reviews.remove(store_id) {
+                self.archived_store_reviews.insert(&(store_id.clone(), generation), &review_ids);
+            }
+            self.return_policies.remove(store_id);
+            let mut history = self.archived_stores.get(store_id).unwrap_or_default();
+            history.push(ArchivedStore {
+                store,
+                closed_by: closed_by.clone(),
+                closed_at: U64::from(env::block_timestamp()),
+                stats: self.stores_stats.remove(store_id).unwrap_or_default(),
+                reputation: self.store_reputations.remove(store_id).unwrap_or_default(),
+            });
+            self.archived_stores.insert(store_id, &history);
+        }
+    }
+
+    // Number of times a store under this name was closed, i.e. the registration new orders
+    // and reviews belong to.
+    pub(crate) fn internal_store_generation(&self, store_id: &AccountId) -> u32 {
+        self.archived_stores.get(store_id).map_or(0, |history| history.len() as u32)
+    }
+
+    // Each registration keeps its orders and reviews under keys of its own, so a store that
+    // reuses a closed store's name never shares storage with the archived collections.
+    pub(crate) fn internal_store_index_hash(&self, store_id: &AccountId) -> Vec<u8> {
+        match self.internal_store_generation(store_id) {
+            0 => env::sha256(store_id.as_bytes()),
+            generation => env::sha256(format!("{}#{}", store_id, generation).as_bytes()),
+        }
+    }
+
+    // Registration an order was placed with, if that store has been closed since.
+    pub(crate) fn internal_closed_store_generation(&self, t: &Transaction) -> Option<u32> {
+        self.archived_stores
+            .get(&t.store_contract_id)?
+            .iter()
+            .position(|archived| u64::from(t.created_at) <= u64::from(archived.closed_at))
+            .map(|generation| generation as u32)
+    }
+
+    pub(crate) fn internal_add_store(&mut self, store: StoreRecord) {
+        self.stores.insert(&store.prefix);
+        self.store_registry.insert(&store.store_id, &store);
+
+        let mut owner_stores = self.stores_by_owner.get(&store.owner_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::OwnerStores {
+                account_hash: env::sha256(store.owner_id.as_bytes()),
+            })
+        });
+        owner_stores.insert(&store.store_id);
+        self.stores_by_owner.insert(&store.owner_id, &owner_stores);
+    }
+
+    pub(crate) fn internal_add_transaction(&mut self, transaction: &Transaction) {
+        let transaction_id: u128 = transaction.transaction_id.into();
+        self.transactions.insert(&transaction_id, &VersionedTransaction::from(transaction.clone()));
+        self.order_references.insert(&transaction.order_reference, &transaction_id);
+
+        let mut buyer_transactions = self.transactions_by_buyer.get(&transaction.buyer_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::BuyerTransactions {
+                account_hash: env::sha256(transaction.buyer_id.as_bytes()),
+            })
+        });
+        buyer_transactions.insert(&transaction_id);
+        self.transactions_by_buyer.insert(&transaction.buyer_id, &buyer_transactions);
+
+        let mut store_transactions = self.transactions_by_store.get(&transaction.store_contract_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::StoreTransactions {
+                account_hash: self.internal_store_index_hash(&transaction.store_contract_id),
+            })
+        });
+        store_transactions.insert(&transaction_id);
+        self.transactions_by_store.insert(&transaction.store_contract_id, &store_transactions);
+
+        if transaction.status.is_open() {
+            let key = Self::open_purchase_key(&transaction.buyer_id, &transaction.store_contract_id, transaction.product_id);
+            self.open_purchases.insert(&key, &transaction_id);
+        }
+
+        self.internal_count_transaction(transaction);
+    }
+
+    // Counts an order towards its store's counters for the first time.
+    pub(crate) fn internal_count_transaction(&mut self, t: &Transaction) {
+        self.internal_track_open_escrow(&t.store_contract_id, false, t.holds_escrow());
+        self.internal_track_locked_escrow(t, 0);
+        self.internal_record_store_stats(t, None);
+        if t.status != TransactionStatus::Approved {
+            self.internal_record_store_stats(t, Some(TransactionStatus::Approved));
+        }
+    }
+
+    pub(crate) fn internal_remove_transaction(&mut self, t: &Transaction) {
+        let transaction_id: u128 = t.transaction_id.into();
+        self.transactions.remove(&transaction_id);
+        self.order_references.remove(&t.order_reference);
+        if let Some(mut buyer_transactions) = self.transactions_by_buyer.get(&t.buyer_id) {
+            buyer_transactions.remove(&transaction_id);
+            if buyer_transactions.is_empty() {
+                self.transactions_by_buyer.remove(&t.buyer_id);
+            } else {
+                self.transactions_by_buyer.insert(&t.buyer_id, &buyer_transactions);
+            }
+        }
+        match self.internal_closed_store_generation(t) {
+            Some(generation) => {
+                let key = (t.store_contract_id.clone(), generation);
+                if let Some(mut store_transactions) = self.archived_store_transactions.get(&key) {
+                    store_transactions.remove(&transaction_id);
+                    if store_transactions.is_empty() {
+                        self.archived_store_transactions.remove(&key);
+                    } else {
+                        self.archived_store_transactions.insert(&key, &store_transactions);
+                    }
+                }
+            }
+            None => {
+                if let Some(mut store_transactions) = self.transactions_by_store.get(&t.store_contract_id) {
+                    store_transactions.remove(&transaction_id);
+                    if store_transactions.is_empty() {
+                        self.transactions_by_store.remove(&t.store_contract_id);
+                    } else {
+                        self.transactions_by_store.insert(&t.store_contract_id, &store_transactions);
+                    }
+                }
+            }
+        }
+        if let Some(mut updates) = self.tracking.remove(&transaction_id) {
+            updates.clear();
+        }
+        self.reviews.remove(&transaction_id);
+        if let Some(cart_id) = t.cart_id {
+            let cart_id = u64::from(cart_id);
+            let mut transaction_ids = self.cart_transactions.get(&cart_id).unwrap_or_default();
+            transaction_ids.retain(|id| id != &t.transaction_id);
+            if transaction_ids.is_empty() {
+                self.cart_transactions.remove(&cart_id);
+            } else {
+                self.cart_transactions.insert(&cart_id, &transaction_ids);
+            }
+        }
+    }
+
+    pub(crate) fn internal_update_transaction(&mut self, transaction: &mut Transaction) {
+        let transaction_id: u128 = transaction.transaction_id.into();
+        match self.transactions.insert(&transaction_id, &VersionedTransaction::from(transaction.clone())) {
+            Some(VersionedTransaction::V2(previous)) => {
+                self.internal_track_open_escrow(
+                    &transaction.store_contract_id,
+                    previous.holds_escrow(),
+                    transaction.holds_escrow(),
+                );
+                self.internal_track_locked_escrow(transaction, previous.escrow_balance());
+                if previous.status != transaction.status {
+                    self.internal_record_store_stats(transaction, Some(previous.status));
+                }
+            }
+            // Orders still in the V1 layout were not counted yet, see `migrate_transactions`.
+            _ => self.internal_count_transaction(transaction),
+        }
+
+        if !transaction.status.is_open() {
+            let key = Self::open_purchase_key(&transaction.buyer_id, &transaction.store_contract_id, transaction.product_id);
+            if self.open_purchases.get(&key) == Some(transaction_id) {
+                self.open_purchases.remove(&key);
+            }
+            self.internal_release_transaction_storage(transaction);
+        }
+    }
+
+    // Closed orders no longer need the buyer's billing details, so they are pruned and only
+    // the bytes that actually frees are handed back to the buyer's storage balance. The rest
+    // of the record stays on chain and stays charged.
+    pub(crate) fn internal_release_transaction_storage(&mut self, transaction: &mut Transaction) {
+        if transaction.hashed_billing_address.is_empty() && transaction.nonce.is_empty() {
+            return;
+        }
+        let transaction_id: u128 = transaction.transaction_id.into();
+        let initial_storage = env::storage_usage();
+        transaction.hashed_billing_address = String::new();
+        transaction.nonce = String::new();
+        self.transactions.insert(&transaction_id, &VersionedTransaction::from(transaction.clone()));
+        let freed = initial_storage.saturating_sub(env::storage_usage()).min(transaction.buyer_storage_bytes);
+        if freed == 0 {
+            return;
+        }
+        transaction.buyer_storage_bytes -= freed;
+        self.transactions.insert(&transaction_id, &VersionedTransaction::from(transaction.clone()));
+        self.internal_release_storage(&transaction.buyer_id, freed);
+    }
+
+    pub(crate) fn internal_storage_balance(&self, account: &StorageAccount) -> StorageBalance {
+        let locked = (STORAGE_REGISTRATION_BYTES + account.used_bytes) as u128 * env::storage_byte_cost();
+        StorageBalance {
+            total: U128::from(account.deposit),
+            available: U128::from(account.deposit.saturating_sub(locked)),
+        }
+    }
+
+    pub(crate) fn internal_assert_storage_available(&self, account_id: &AccountId, bytes: u64) {
+        let account = self.storage_accounts.get(account_id).unwrap_or_else(|| {
+            env::panic_str(&format!("{} must register a storage deposit first", account_id))
+        });
+        let required = bytes as u128 * env::storage_byte_cost();
+        let available: u128 = self.internal_storage_balance(&account).available.into();
+        assert!(
+            available >= required,
+            "{} needs at least {} yoctoNEAR of available storage balance",
+            account_id,
+            required
+        );
+    }
+
+    // Charging happens once the bytes are already written, so it never fails: an account that
+    // was drained in the meantime simply ends up with no available balance.
+    pub(crate) fn internal_charge_storage(&mut self, account_id: &AccountId, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            account.used_bytes += bytes;
+            self.storage_accounts.insert(account_id, &account);
+        }
+    }
+
+    pub(crate) fn internal_release_storage(&mut self, account_id: &AccountId, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            account.used_bytes = account.used_bytes.saturating_sub(bytes);
+            self.storage_accounts.insert(account_id, &account);
+        }
+    }
+
+    // Counts the order against its store once per lifecycle step: `previous_status` is
+    // `None` for a freshly created order, otherwise the status it is moving away from.
+    pub(crate) fn internal_record_store_stats(&mut self, t: &Transaction, previous_status: Option<TransactionStatus>) {
+        let mut stats = self.stores_stats.get(&t.store_contract_id).unwrap_or_default();
+        if previous_status.is_none() {
+            stats.order_count += 1;
+            let locked: u128 = t.buyer_value_locked.into();
+            match &t.ft_token_id {
+                Some(ft_token_id) => {
+                    let volume = stats.ft_gross_volume.get(ft_token_id).map_or(0, |v| v.0);
+                    stats.ft_gross_volume.insert(ft_token_id.clone(), U128::from(volume + locked));
+                }
+                None => stats.gross_volume = U128::from(stats.gross_volume.0 + locked),
+            }
+        } else {
+            match t.status {
+                TransactionStatus::Approved => {}
+                TransactionStatus::Shipped => {
+                    stats.shipped_count += 1;
+                    if let Some(shipped_at) = t.shipped_at {
+                        let ship_time = u64::from(shipped_at).saturating_sub(t.created_at.into());
+                        stats.total_ship_time = U64::from(u64::from(stats.total_ship_time) + ship_time);
+                        stats.average_ship_time = U64::from(u64::from(stats.total_ship_time) / stats.shipped_count);
+                    }
+                }
+                TransactionStatus::Delivered => stats.delivered_count += 1,
+                TransactionStatus::Disputed => stats.disputed_count += 1,
+                TransactionStatus::Resolved => stats.resolved_count += 1,
+                TransactionStatus::Canceled => stats.refunded_count += 1,
+                TransactionStatus::ReturnRequested => stats.return_requested_count += 1,
+                TransactionStatus::Returned => stats.returned_count += 1,
+            }
+        }
+        self.stores_stats.insert(&t.store_contract_id, &stats);
+    }
+
+    // `from_index` and `limit` page over the underlying collection so a view call
+    // never scans more than `limit` records; the filter is applied within the page.
+    fn paginate_transactions(
+        &self,
+        transaction_ids: impl Iterator<Item = u128>,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+        filter: Option<TransactionFilter>,
+    ) -> TransactionPage {
+        let start = u128::from(from_index.unwrap_or(U128(0))) as usize;
+        let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize;
+        let filter = filter.unwrap_or_default();
+        let mut transaction_ids = transaction_ids.skip(start).peekable();
+        let mut transactions = vec![];
+        let mut next_index = start;
+        // Orders are scanned until `limit` of them match, but never more than a bounded
+        // window, so a sparse filter returns a short page and the cursor to continue from.
+        while transactions.len() < limit && next_index - start < MAX_PAGE_SCAN as usize {
+            match transaction_ids.next() {
+                Some(transaction_id) => {
+                    next_index += 1;
+                    if let Some(t) = self.internal_get_transaction(transaction_id).filter(|t| filter.matches(t)) {
+                        transactions.push(t);
+                    }
+                }
+                None => break,
+            }
+        }
+        TransactionPage {
+            transactions,
+            next_index: transaction_ids.peek().map(|_| U128(next_index as u128)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn setup() -> PiparContractFactory {
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).build());
+        PiparContractFactory::new(None)
+    }
+
+    fn transaction(locked: u128, quantity: u32) -> Transaction {
+        Transaction {
+            transaction_id: U128(1),
+            order_reference: String::from("PIP-000001-ABCDEF"),
+            created_at: U64(0),
+            product_id: U64(1),
+            store_contract_id: accounts(1),
+            buyer_id: accounts(2),
+            buyer_value_locked: U128(locked),
+            price: locked,
+            token_id: String::from("1:1"),
+            timeout: U128(7),
+            affiliate: true,
+            affiliate_id: Some(accounts(3)),
+            affiliate_percentage: Some(7),
+            is_discount: false,
+            is_reward: false,
+            is_keypom: false,
+            status: TransactionStatus::Shipped,
+            hashed_billing_address: String::new(),
+            nonce: String::new(),
+            ipfs: String::new(),
+            dispute_resolution: None,
+            cancellation_requested: false,
+            ft_token_id: None,
+            shipped_at: None,
+            confirmation_deadline: None,
+            platform_fee_bps: 250,
+            referrer_id: Some(accounts(4)),
+            referral_fee_bps: 3_333,
+            royalties: vec![(accounts(5), 1_111)],
+            buyer_storage_bytes: 0,
+            store_storage_bytes: 0,
+            cart_id: None,
+            quantity,
+            token_ids: vec![String::from("1:1")],
+            shipped_quantity: quantity,
+            delivered_quantity: 0,
+            released_value: U128(0),
+            return_policy: None,
+            return_deadline: None,
+            return_request: None,
+        }
+    }
+
+    fn marketplace_data(price: u128, token_ids: Vec<String>) -> MarketplaceData {
+        MarketplaceData {
+            price,
+            affiliate: false,
+            affiliate_id: None,
+            affiliate_percentage: None,
+            token_id: String::from("1:1"),
+            token_owner: accounts(2),
+            store_owner: accounts(1),
+            royalty: HashMap::new(),
+            token_ids,
+        }
+    }
+
+    fn total(payouts: &[Payout]) -> u128 {
+        payouts.iter().map(|payout| u128::from(payout.amount)).sum()
+    }
+
+    #[test]
+    fn upgrade_gas_covers_deploying_code_of_the_factory_size() {
+        // The factory bundles the store code, so its own code is at least that large.
+        let code_len = BUNDLED_STORE_CODE.len() + 200_000;
+        let deploy = deploy_gas(code_len);
+        assert!(deploy.0 > tgas(60).0);
+        assert_eq!(upgrade_migrate_gas(tgas(60), tgas(5), code_len), None);
+        let migrate_gas = upgrade_migrate_gas(tgas(300), tgas(20), code_len).unwrap();
+        assert_eq!(migrate_gas.0 + deploy.0 + MIGRATE_GAS_RESERVE.0 + tgas(20).0, tgas(300).0);
+        assert!(migrate_gas.0 > tgas(200).0);
+    }
+
+    #[test]
+    fn store_upgrade_gas_follows_the_code_size() {
+        let code_len = BUNDLED_STORE_CODE.len();
+        assert_eq!(store_upgrade_gas(code_len).0, deploy_gas(code_len).0 + STORE_MIGRATE_GAS.0);
+        assert!(store_upgrade_gas(code_len).0 > tgas(50).0);
+        // Stores running code of the bundled size can still be upgraded a few per call.
+        assert!(store_upgrade_batch_gas(code_len, 3).0 < tgas(290).0);
+        assert!(store_upgrade_batch_gas(code_len, MAX_STORE_UPGRADE_BATCH).0 > tgas(300).0);
+    }
+
+    #[test]
+    fn per_token_calls_of_the_largest_order_fit_in_a_transaction() {
+        let max_tokens = MAX_ORDER_QUANTITY as u64;
+        let delivery = PGAS.0 + COMPLETE_PURCHASE_GAS.0 + UNLOCK_TOKEN_GAS.0 * max_tokens;
+        assert!(delivery < tgas(250).0);
+        let rejection = PGAS.0 + BURN_TOKEN_GAS.0 * max_tokens + tgas(30).0;
+        assert!(rejection < tgas(250).0);
+    }
+
+    #[test]
+    fn bps_of_rounds_down() {
+        assert_eq!(bps_of(10_000, 250), 250);
+        assert_eq!(bps_of(9_999, 1), 0);
+        assert_eq!(bps_of(12_345, 10_000), 12_345);
+        assert_eq!(bps_of(0, 5_000), 0);
+    }
+
+    #[test]
+    fn bps_of_does_not_overflow() {
+        assert_eq!(bps_of(u128::MAX, 10_000), u128::MAX);
+        assert_eq!(bps_of(u128::MAX, 5_000), u128::MAX / 2);
+    }
+
+    #[test]
+    fn value_of_units_adds_up_to_the_locked_amount() {
+        let t = transaction(100, 3);
+        assert_eq!(t.value_of_units(0), 0);
+        assert_eq!(t.value_of_units(1), 33);
+        assert_eq!(t.value_of_units(2), 66);
+        assert_eq!(t.value_of_units(3), 100);
+
+        let t = transaction(u128::MAX, 7);
+        assert_eq!(t.value_of_units(7), u128::MAX);
+        assert!(t.value_of_units(3) <= t.value_of_units(4));
+    }
+
+    #[test]
+    fn release_payouts_sum_to_the_released_amount() {
+        let contract = setup();
+        let t = transaction(1_000_000_000_000_000_000_000_007, 1);
+        let payouts = contract.internal_release_payouts(&t, t.buyer_value_locked.into());
+        assert_eq!(total(&payouts), u128::from(t.buyer_value_locked));
+        assert_eq!(payouts[0].kind, PayoutKind::Store);
+        for kind in [PayoutKind::Affiliate, PayoutKind::Royalty, PayoutKind::Referrer, PayoutKind::Platform] {
+            assert_eq!(payouts.iter().filter(|payout| payout.kind == kind).count(), 1);
+        }
+    }
+
+    #[test]
+    fn partial_releases_sum_to_the_locked_amount() {
+        let contract = setup();
+        let locked = 1_000_000_000_000_000_000_000_001;
+        let t = transaction(locked, 7);
+        let mut delivered = 0;
+        let mut paid_out = 0;
+        for units in [1, 2, 3, 1] {
+            let value = t.value_of_units(delivered + units) - t.value_of_units(delivered);
+            let payouts = contract.internal_release_payouts(&t, value);
+            assert_eq!(total(&payouts), value);
+            paid_out += total(&payouts);
+            delivered += units;
+        }
+        assert_eq!(delivered, 7);
+        assert_eq!(paid_out, locked);
+    }
+
+    #[test]
+    fn release_payouts_of_nothing_are_empty() {
+        let contract = setup();
+        let t = transaction(1_000, 1);
+        let payouts = contract.internal_release_payouts(&t, 0);
+        assert!(payouts.iter().all(|payout| payout.amount == U128(0)));
+    }
+
+    #[test]
+    fn bundled_store_code_lacks_the_optional_entry_points() {
+        let exports = wasm_exported_functions(BUNDLED_STORE_CODE).unwrap();
+        assert!(exports.iter().any(|name| name == "nft_mint"));
+        assert!(exports.iter().any(|name| name == "assert_store_owner"));
+        assert!(PiparContractFactory::store_entry_points(BUNDLED_STORE_CODE).is_empty());
+        assert!(wasm_exported_functions(b"not wasm").is_none());
+    }
+
+    #[test]
+    fn pruning_a_settled_order_releases_all_of_its_storage() {
+        let mut contract = setup();
+        contract.storage_accounts.insert(&accounts(2), &StorageAccount { deposit: 0, used_bytes: 0 });
+        let initial_storage = env::storage_usage();
+        let mut t = Transaction { status: TransactionStatus::Approved, ..transaction(1_000, 1) };
+        contract.internal_add_transaction(&t);
+        contract.internal_append_tracking(&t, TrackingUpdate {
+            carrier: None,
+            tracking_code_hash: None,
+            ipfs: String::from("ipfs"),
+            timestamp: U64(0),
+            author_id: accounts(1),
+        });
+        t.buyer_storage_bytes = env::storage_usage() - initial_storage;
+        contract.internal_charge_storage(&accounts(2), t.buyer_storage_bytes);
+        contract.internal_update_transaction(&mut t);
+
+        t.status = TransactionStatus::Canceled;
+        t.released_value = t.buyer_value_locked;
+        contract.internal_update_transaction(&mut t);
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.prune_transaction(U128(1));
+        assert_eq!(contract.storage_accounts.get(&accounts(2)).unwrap().used_bytes, 0);
+        assert!(contract.internal_get_transaction(1).is_none());
+        assert!(contract.get_tracking(U128(1)).is_empty());
+        assert!(contract.transactions_by_buyer.get(&accounts(2)).is_none());
+    }
+
+    fn store_record(contract: &PiparContractFactory, store_id: AccountId) -> StoreRecord {
+        StoreRecord {
+            store_id,
+            prefix: String::from("shop"),
+            owner_id: accounts(2),
+            name: String::from("Shop"),
+            category: String::new(),
+            city: String::new(),
+            state: String::new(),
+            country: String::new(),
+            code_hash: contract.default_store_code_hash.clone(),
+            created_at: U64(0),
+            is_keypom: false,
+        }
+    }
+
+    fn register_store(contract: &mut PiparContractFactory, store_id: AccountId) {
+        let store = store_record(contract, store_id.clone());
+        contract.store_registry.insert(&store_id, &store);
+    }
+
+    fn import_store_as(role: Role) -> PiparContractFactory {
+        let mut contract = setup();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(contract.owner_id.clone())
+            .attached_deposit(1)
+            .build());
+        contract.grant_role(accounts(3), role);
+        contract.stores.insert(&String::from("shop"));
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).predecessor_account_id(accounts(3)).build());
+        let store = store_record(&contract, "shop.alice".parse().unwrap());
+        contract.import_store(store);
+        contract
+    }
+
+    #[test]
+    fn verifiers_import_stores_deployed_before_the_registry() {
+        let contract = import_store_as(Role::Verifier);
+        assert!(contract.store_registry.get(&"shop.alice".parse().unwrap()).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only an account with the Verifier role can call this method")]
+    fn admins_cannot_import_stores() {
+        import_store_as(Role::Admin);
+    }
+
+    #[test]
+    fn closed_stores_are_archived_per_registration() {
+        let mut contract = setup();
+        register_store(&mut contract, accounts(1));
+        let first = Transaction {
+            status: TransactionStatus::Delivered,
+            released_value: U128(1_000),
+            ..transaction(1_000, 1)
+        };
+        contract.internal_add_transaction(&first);
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).block_timestamp(10).build());
+        contract.internal_archive_store(&accounts(1), &accounts(2));
+        assert_eq!(contract.get_seller_transaction_count(accounts(1)), 0);
+        assert_eq!(contract.get_store_stats(accounts(1)).order_count, 0);
+
+        // The name is registered again and closed a second time.
+        register_store(&mut contract, accounts(1));
+        let second = Transaction {
+            transaction_id: U128(2),
+            order_reference: String::from("PIP-000002-ABCDEF"),
+            created_at: U64(20),
+            ..first.clone()
+        };
+        contract.internal_add_transaction(&second);
+        assert_eq!(contract.get_seller_transaction_count(accounts(1)), 1);
+        testing_env!(VMContextBuilder::new().current_account_id(accounts(0)).block_timestamp(30).build());
+        contract.internal_archive_store(&accounts(1), &accounts(2));
+
+        let history = contract.get_archived_store_history(accounts(1));
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].stats.order_count, 1);
+        assert_eq!(history[1].stats.order_count, 1);
+        assert_eq!(contract.internal_closed_store_generation(&first), Some(0));
+        assert_eq!(contract.internal_closed_store_generation(&second), Some(1));
+
+        contract.internal_remove_transaction(&first);
+        assert!(contract.archived_store_transactions.get(&(accounts(1), 0)).is_none());
+        assert_eq!(contract.archived_store_transactions.get(&(accounts(1), 1)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stores_without_burn_support_sell_single_units() {
+        let mut contract = setup();
+        register_store(&mut contract, accounts(1));
+        assert!(!contract.internal_store_supports(&accounts(1), "burn_token"));
+        contract.internal_assert_order_quantity(&accounts(1), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "only sells one unit per order")]
+    fn stores_without_burn_support_reject_several_units() {
+        let mut contract = setup();
+        register_store(&mut contract, accounts(1));
+        contract.internal_assert_order_quantity(&accounts(1), 2);
+    }
+
+    #[test]
+    fn filtered_pages_continue_from_their_cursor() {
+        let mut contract = setup();
+        for transaction_id in 1..=5u128 {
+            let status = if transaction_id % 2 == 0 { TransactionStatus::Delivered } else { TransactionStatus::Approved };
+            contract.internal_add_transaction(&Transaction {
+                transaction_id: U128(transaction_id),
+                order_reference: format!("PIP-{:06}-ABCDEF", transaction_id),
+                product_id: U64(transaction_id as u64),
+                status,
+                ..transaction(1_000, 1)
+            });
+        }
+        let delivered = || Some(TransactionFilter { status: Some(TransactionStatus::Delivered), ..Default::default() });
+
+        let page = contract.get_buyer_transactions(accounts(2), None, Some(1), delivered());
+        assert_eq!(page.transactions.len(), 1);
+        assert_eq!(page.transactions[0].transaction_id, U128(2));
+        assert_eq!(page.next_index, Some(U128(2)));
+
+        let page = contract.get_buyer_transactions(accounts(2), page.next_index, Some(5), delivered());
+        assert_eq!(page.transactions.len(), 1);
+        assert_eq!(page.transactions[0].transaction_id, U128(4));
+        assert_eq!(page.next_index, None);
+    }
+
+    #[test]
+    fn open_escrow_count_follows_the_order_lifecycle() {
+        let mut contract = setup();
+        let mut t = transaction(1_000, 1);
+        contract.internal_add_transaction(&t);
+        assert_eq!(contract.get_open_escrow_count(accounts(1)), 1);
+
+        // Delivered orders still hold escrow until their funds are released.
+        t.status = TransactionStatus::Delivered;
+        contract.internal_update_transaction(&mut t);
+        assert_eq!(contract.get_open_escrow_count(accounts(1)), 1);
+        t.released_value = t.buyer_value_locked;
+        contract.internal_update_transaction(&mut t);
+        assert_eq!(contract.get_open_escrow_count(accounts(1)), 0);
+
+        let mut t = Transaction { transaction_id: U128(2), status: TransactionStatus::Approved, ..transaction(1_000, 1) };
+        contract.internal_add_transaction(&t);
+        assert_eq!(contract.get_open_escrow_count(accounts(1)), 1);
+        t.status = TransactionStatus::Canceled;
+        t.released_value = t.buyer_value_locked;
+        contract.internal_update_transaction(&mut t);
+        assert_eq!(contract.get_open_escrow_count(accounts(1)), 0);
+    }
+
+    #[test]
+    fn locked_escrow_follows_released_value() {
+        let mut contract = setup();
+        let mut t = transaction(1_000, 2);
+        contract.internal_add_transaction(&t);
+        assert_eq!(contract.get_locked_escrow(), U128(1_000));
+
+        t.released_value = U128(t.value_of_units(1));
+        contract.internal_update_transaction(&mut t);
+        assert_eq!(contract.get_locked_escrow(), U128(500));
+
+        // Escrow held in fungible tokens does not count towards the NEAR balance.
+        let ft = Transaction { transaction_id: U128(2), ft_token_id: Some(accounts(3)), ..transaction(1_000, 1) };
+        contract.internal_add_transaction(&ft);
+        assert_eq!(contract.get_locked_escrow(), U128(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "unable to cover its storage and the escrow it holds")]
+    fn fee_withdrawals_cannot_spend_escrow() {
+        let mut contract = setup();
+        contract.internal_add_transaction(&transaction(1_000, 1));
+        contract.fee_balances.insert(&None, &500);
+        let storage_usage = env::storage_usage();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(contract.owner_id.clone())
+            .storage_usage(storage_usage)
+            .account_balance(storage_usage as u128 * env::storage_byte_cost() + 1_000 + 499)
+            .build());
+        contract.withdraw_fees(None, None);
+    }
+
+    #[test]
+    fn closing_an_order_releases_only_the_pruned_bytes() {
+        let mut contract = setup();
+        contract.storage_accounts.insert(&accounts(2), &StorageAccount { deposit: 0, used_bytes: 0 });
+        let initial_storage = env::storage_usage();
+        let mut t = Transaction {
+            status: TransactionStatus::Approved,
+            hashed_billing_address: "a".repeat(64),
+            nonce: "b".repeat(32),
+            ..transaction(1_000, 1)
+        };
+        contract.internal_add_transaction(&t);
+        t.buyer_storage_bytes = env::storage_usage() - initial_storage;
+        contract.internal_charge_storage(&accounts(2), t.buyer_storage_bytes);
+        contract.internal_update_transaction(&mut t);
+
+        let charged = t.buyer_storage_bytes;
+        t.status = TransactionStatus::Canceled;
+        t.released_value = t.buyer_value_locked;
+        contract.internal_update_transaction(&mut t);
+        // Only the billing address and nonce are freed, the rest of the record stays charged.
+        let used_bytes = contract.storage_accounts.get(&accounts(2)).unwrap().used_bytes;
+        assert!(t.hashed_billing_address.is_empty() && t.nonce.is_empty());
+        assert_eq!(charged - used_bytes, 96);
+        assert_eq!(used_bytes, t.buyer_storage_bytes);
+        assert_eq!(contract.internal_get_transaction(1).unwrap().buyer_storage_bytes, used_bytes);
+    }
+
+    fn legacy_transaction(created_at: u128, status: TransactionStatus) -> LegacyTransaction {
+        LegacyTransaction {
+            transaction_id: U128(created_at),
+            product_id: U64(1),
+            store_contract_id: accounts(1),
+            buyer_id: accounts(2),
+            buyer_value_locked: U128(1_000),
+            price: 1_000,
+            token_id: String::from("1:1"),
+            timeout: U128(7),
+            affiliate: false,
+            affiliate_id: None,
+            affiliate_percentage: None,
+            is_discount: false,
+            is_reward: false,
+            is_keypom: false,
+            status,
+            hashed_billing_address: String::new(),
+            nonce: String::new(),
+            ipfs: String::new(),
+        }
+    }
+
+    #[test]
+    fn legacy_orders_migrate_in_batches_and_keep_their_order() {
+        let mut contract = setup();
+        let mut transactions = Vector::new(b"v".to_vec());
+        for created_at in [100, 200, 300] {
+            transactions.push(&legacy_transaction(created_at, TransactionStatus::Shipped));
+        }
+        contract.next_transaction_id = 4;
+        contract.pending_migration = Some(PendingMigration::Legacy { transactions });
+
+        assert_eq!(contract.migrate_transactions(Some(2)), U64(1));
+        assert_eq!(contract.get_pending_migration_count(), U64(1));
+        assert_eq!(contract.migrate_transactions(Some(2)), U64(0));
+        assert!(contract.pending_migration.is_none());
+        for (transaction_id, created_at) in [(1, 100), (2, 200), (3, 300)] {
+            let t = contract.internal_get_transaction(transaction_id).unwrap();
+            assert_eq!(t.created_at, U64(created_at));
+            assert!(t.confirmation_deadline.is_some());
+        }
+        assert_eq!(contract.get_open_escrow_count(accounts(1)), 3);
+        assert_eq!(contract.internal_next_transaction_id(), 4);
+    }
+
+    #[test]
+    fn v1_orders_are_counted_once_however_they_are_migrated() {
+        let mut contract = setup();
+        for transaction_id in 1..=3 {
+            let t = legacy_transaction(0, TransactionStatus::Approved)
+                .into_v1(transaction_id, contract.internal_order_reference(transaction_id));
+            contract.transactions.insert(&transaction_id, &VersionedTransaction::V1(Box::new(t)));
+        }
+        contract.pending_migration = Some(PendingMigration::V1 { next_index: 0, end_index: 3 });
+
+        // An order updated before its batch runs is counted by the update.
+        let mut t = contract.internal_get_transaction(3).unwrap();
+        t.status = TransactionStatus::Canceled;
+        t.released_value = t.buyer_value_locked;
+        contract.internal_update_transaction(&mut t);
+        assert_eq!(contract.migrate_transactions(None), U64(0));
+        assert_eq!(contract.get_open_escrow_count(accounts(1)), 2);
+        assert_eq!(contract.get_store_stats(accounts(1)).order_count, 3);
+    }
+
+    #[test]
+    fn v1_transactions_read_as_the_current_layout() {
+        let v1 = TransactionV1 {
+            transaction_id: U128(1),
+            order_reference: String::from("PIP-000001-ABCDEF"),
+            created_at: U64(0),
+            product_id: U64(1),
+            store_contract_id: accounts(1),
+            buyer_id: accounts(2),
+            buyer_value_locked: U128(1_000),
+            price: 1_000,
+            token_id: String::from("1:1"),
+            timeout: U128(7),
+            affiliate: false,
+            affiliate_id: None,
+            affiliate_percentage: None,
+            is_discount: false,
+            is_reward: false,
+            is_keypom: false,
+            status: TransactionStatus::Shipped,
+            hashed_billing_address: String::new(),
+            nonce: String::new(),
+            ipfs: String::new(),
+            dispute_resolution: None,
+            cancellation_requested: false,
+            ft_token_id: None,
+        };
+        let mut bytes = vec![0];
+        bytes.extend(v1.try_to_vec().unwrap());
+        let t = Transaction::from(VersionedTransaction::try_from_slice(&bytes).unwrap());
+        assert_eq!((t.quantity, t.shipped_quantity, t.delivered_quantity), (1, 1, 0));
+        assert_eq!(t.token_ids, vec![String::from("1:1")]);
+        assert!(t.holds_escrow());
+
+        // Boxing the variant does not change how the current layout is stored.
+        let mut bytes = vec![1];
+        bytes.extend(t.try_to_vec().unwrap());
+        assert_eq!(VersionedTransaction::from(t).try_to_vec().unwrap(), bytes);
+    }
+
+    #[test]
+    fn check_mint_rejects_wrong_token_counts() {
+        let result = marketplace_data(10, vec![]);
+        assert!(check_mint(&result, 10, 1).is_ok());
+        assert!(check_mint(&result, 30, 3).is_err());
+        let result = marketplace_data(10, vec![String::from("1:1"), String::from("1:2"), String::from("1:3")]);
+        assert!(check_mint(&result, 30, 3).is_ok());
+    }
+
+    #[test]
+    fn check_mint_rejects_underpayment() {
+        let result = marketplace_data(10, vec![String::from("1:1"), String::from("1:2")]);
+        assert!(check_mint(&result, 19, 2).is_err());
+        assert!(check_mint(&result, 20, 2).is_ok());
+        let result = marketplace_data(u128::MAX, vec![String::from("1:1"), String::from("1:2")]);
+        assert!(check_mint(&result, u128::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn check_mint_rejects_shares_above_the_seller_funds() {
+        let mut result = marketplace_data(10, vec![]);
+        result.affiliate = true;
+        result.affiliate_id = Some(accounts(3));
+        result.affiliate_percentage = Some(60);
+        result.royalty.insert(accounts(5), 4_000);
+        assert!(check_mint(&result, 10, 1).is_ok());
+        result.royalty.insert(accounts(4), 1);
+        assert!(check_mint(&result, 10, 1).is_err());
+        // Shares of an affiliate without an account are never paid out.
+        result.affiliate_id = None;
+        assert!(check_mint(&result, 10, 1).is_ok());
+    }
 }